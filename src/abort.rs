@@ -0,0 +1,141 @@
+use super::*;
+
+const REQUEST_TYPE: u8 = 0b00100001;
+const DFU_ABORT: u8 = 6;
+
+/// Returns `true` if a previous session left the device in the middle of a transfer
+/// (`dfuDNLOAD-IDLE` or `dfuUPLOAD-IDLE`).
+pub(crate) fn interrupted(state: State) -> bool {
+    matches!(state, State::DfuDnloadIdle | State::DfuUploadIdle)
+}
+
+/// Command that aborts the current operation and brings the device back to `dfuIDLE`.
+///
+/// The commands that require the device in `dfuIDLE` start with it when a previous session has
+/// been interrupted in the middle of a transfer: the status of the device is then queried again
+/// to start over.
+#[must_use]
+pub struct Abort<'dfu, IO: DfuIo, T> {
+    pub(crate) dfu: &'dfu DfuSansIo<IO>,
+    pub(crate) chained_command: T,
+}
+
+impl<'dfu, IO: DfuIo, T> Abort<'dfu, IO, T> {
+    /// Send the command `DFU_ABORT` to the device.
+    pub fn abort(self) -> Result<(T, IO::Write), IO::Error> {
+        let res = self.dfu.io.write_control(REQUEST_TYPE, DFU_ABORT, 0, &[])?;
+        let next = self.chained_command;
+
+        Ok((next, res))
+    }
+}
+
+impl<'dfu, IO, T> Abort<'dfu, IO, get_status::GetStatus<'dfu, IO, T>>
+where
+    IO: DfuIo,
+    T: ChainedCommand<Arg = get_status::GetStatusMessage>,
+{
+    /// Abort the interrupted transfer and query the status again to start over with `start`.
+    pub(crate) fn start_over(dfu: &'dfu DfuSansIo<IO>, start: T) -> Self {
+        Self {
+            dfu,
+            chained_command: get_status::GetStatus {
+                dfu,
+                chained_command: start,
+            },
+        }
+    }
+}
+
+/// A step when starting a command that requires the device in `dfuIDLE`.
+pub enum Step<'dfu, IO, S, T>
+where
+    IO: DfuIo,
+    S: ChainedCommand<Arg = get_status::GetStatusMessage>,
+{
+    /// The device is in `dfuIDLE`.
+    Ready(T),
+    /// A previous transfer must be aborted before starting over.
+    Abort(Abort<'dfu, IO, get_status::GetStatus<'dfu, IO, S>>),
+}
+
+impl<'dfu, IO, S, T> Step<'dfu, IO, S, T>
+where
+    IO: DfuIo,
+    S: ChainedCommand<Arg = get_status::GetStatusMessage>,
+{
+    /// Returns `ready` if the device is in `dfuIDLE` or aborts the transfer of an interrupted
+    /// session to start over with `start_over`, unless it has already been aborted.
+    pub(crate) fn new(
+        dfu: &'dfu DfuSansIo<IO>,
+        state: State,
+        aborted: bool,
+        ready: impl FnOnce() -> T,
+        start_over: impl FnOnce() -> S,
+    ) -> Result<Self, Error> {
+        if state == State::DfuIdle {
+            Ok(Step::Ready(ready()))
+        } else if interrupted(state) && !aborted {
+            Ok(Step::Abort(Abort::start_over(dfu, start_over())))
+        } else {
+            Err(Error::InvalidState {
+                got: state,
+                expected: State::DfuIdle,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{Device, Request};
+
+    #[test]
+    fn interrupted_session() {
+        for state in [State::DfuDnloadIdle, State::DfuUploadIdle] {
+            let device = Device::new(Protocol::Dfu);
+            device.state.set(state);
+            let mut dfu = sync::DfuSync::new(&device, 0);
+            dfu.download_from_slice(&[0x42; 300]).unwrap();
+            assert_eq!(
+                device.take_requests()[..2],
+                [Request::Abort, Request::Dnload(0, 256)]
+            );
+
+            device.state.set(state);
+            assert_eq!(dfu.upload_to_vec(300).unwrap(), [0x42; 300]);
+            assert_eq!(
+                device.take_requests()[..3],
+                [Request::ClrStatus, Request::Abort, Request::Upload(0, 256)]
+            );
+        }
+
+        // a DfuSe download ends in dfuDNLOAD-IDLE
+        let device = Device::new(Protocol::DfuSe);
+        let mut dfu = sync::DfuSync::new(&device, mock::ADDRESS);
+        dfu.download_from_slice(&[0x42; 300]).unwrap();
+        device.take_requests();
+        dfu.mass_erase().unwrap();
+        assert_eq!(
+            device.take_requests(),
+            [Request::ClrStatus, Request::Abort, Request::MassErase]
+        );
+        dfu.get_commands().unwrap();
+        assert_eq!(
+            device.take_requests(),
+            [Request::ClrStatus, Request::Abort, Request::Upload(0, 256)]
+        );
+        device.state.set(State::DfuUploadIdle);
+        dfu.read_unprotect().unwrap();
+        assert_eq!(
+            device.take_requests(),
+            [
+                Request::ClrStatus,
+                Request::Abort,
+                Request::ReadUnprotect,
+                Request::Reconnect,
+            ]
+        );
+    }
+}
@@ -23,3 +23,21 @@ impl<'dfu, IO: DfuIo, T> Detach<'dfu, IO, T> {
         Ok((next, res))
     }
 }
+
+/// Bring the device back in DFU mode after `dfuDETACH` has been sent.
+#[must_use]
+pub struct Attach<'dfu, IO: DfuIo, T> {
+    pub(crate) dfu: &'dfu DfuSansIo<IO>,
+    pub(crate) chained_command: T,
+}
+
+impl<'dfu, IO: DfuIo, T> Attach<'dfu, IO, T> {
+    /// Returns the next step to re-enumerate the device in DFU mode.
+    ///
+    /// The device must be reset by the host unless it detaches itself (`bitWillDetach`).
+    pub fn next(self) -> reset::Step<'dfu, IO, T> {
+        let detach_timeout = self.dfu.io.functional_descriptor().detach_timeout;
+
+        reset::Step::new(self.dfu, detach_timeout as u64, self.chained_command)
+    }
+}
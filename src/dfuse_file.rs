@@ -0,0 +1,504 @@
+use displaydoc::Display;
+#[cfg(any(feature = "std", test))]
+use std::prelude::v1::*;
+#[cfg(any(feature = "std", test))]
+use thiserror::Error;
+
+const PREFIX_SIGNATURE: &[u8] = b"DfuSe";
+const PREFIX_VERSION: u8 = 0x01;
+const PREFIX_LEN: usize = 11;
+const TARGET_SIGNATURE: &[u8] = b"Target";
+const TARGET_NAME_LEN: usize = 255;
+const TARGET_PREFIX_LEN: usize = 274;
+const ELEMENT_PREFIX_LEN: usize = 8;
+#[cfg(any(feature = "std", test))]
+const DFUSE_VERSION: u16 = 0x011a;
+
+/// Error while parsing or writing a DfuSe file.
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(any(feature = "std", test), derive(Error))]
+pub enum Error {
+    /// invalid prefix signature
+    InvalidSignature,
+    /// unsupported DfuSe version: {0}
+    UnsupportedVersion(u8),
+    /// the DFU suffix is missing
+    InvalidSuffix,
+    /// invalid DFU suffix: {0}
+    Suffix(crate::suffix::Error),
+    /// invalid signature for the target {0}
+    InvalidTargetSignature(u8),
+    /// the size of the target {0} does not match its elements
+    InvalidTargetSize(u8),
+    /// the file is truncated
+    UnexpectedEnd,
+    /// unexpected data after the last target
+    TrailingData,
+    /// no target for the alternate setting {0}
+    TargetNotFound(u8),
+    /// too many targets: {0}
+    TooManyTargets(usize),
+    /// the name of the target {0} is too long
+    NameTooLong(u8),
+    /// the file exceeds the maximum size of a DfuSe file
+    TooLarge,
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    let mut buffer = [0; 4];
+    buffer.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_le_bytes(buffer)
+}
+
+fn split_at(bytes: &[u8], len: usize) -> Result<(&[u8], &[u8]), Error> {
+    if bytes.len() < len {
+        return Err(Error::UnexpectedEnd);
+    }
+
+    Ok(bytes.split_at(len))
+}
+
+/// A DfuSe file: the prefix, the targets with their image elements and the DFU suffix.
+///
+/// The file is fully validated when parsed and borrows the data of the elements.
+#[derive(Debug, Clone, Copy)]
+pub struct DfuseFile<'a> {
+    targets: &'a [u8],
+    targets_count: u8,
+    suffix: crate::suffix::Suffix,
+}
+
+impl<'a> DfuseFile<'a> {
+    /// Parse a DfuSe file.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        let (image, suffix) = match crate::suffix::strip(bytes).map_err(Error::Suffix)? {
+            (image, Some(suffix)) => (image, suffix),
+            (_, None) => return Err(Error::InvalidSuffix),
+        };
+
+        let (prefix, mut targets) = split_at(image, PREFIX_LEN)?;
+        if &prefix[..5] != PREFIX_SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
+        if prefix[5] != PREFIX_VERSION {
+            return Err(Error::UnsupportedVersion(prefix[5]));
+        }
+        let targets_count = prefix[10];
+
+        let all_targets = targets;
+        for index in 0..targets_count {
+            Target::parse(&mut targets, index)?;
+        }
+        if !targets.is_empty() {
+            return Err(Error::TrailingData);
+        }
+
+        Ok(Self {
+            targets: all_targets,
+            targets_count,
+            suffix,
+        })
+    }
+
+    /// Returns the DFU suffix of the file.
+    pub fn suffix(&self) -> crate::suffix::Suffix {
+        self.suffix
+    }
+
+    /// Returns the targets of the file.
+    pub fn targets(&self) -> Targets<'a> {
+        Targets {
+            bytes: self.targets,
+            remaining: self.targets_count,
+        }
+    }
+
+    /// Returns the target of the alternate setting `alt_setting`.
+    pub fn target(&self, alt_setting: u8) -> Result<Target<'a>, Error> {
+        self.targets()
+            .find(|target| target.alt_setting() == alt_setting)
+            .ok_or(Error::TargetNotFound(alt_setting))
+    }
+}
+
+/// Iterator over the targets of a [`DfuseFile`].
+#[derive(Debug, Clone)]
+pub struct Targets<'a> {
+    bytes: &'a [u8],
+    remaining: u8,
+}
+
+impl<'a> Iterator for Targets<'a> {
+    type Item = Target<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        // the targets have been validated when parsing the file
+        Target::parse(&mut self.bytes, 0).ok()
+    }
+}
+
+/// A target of a DfuSe file: the image of an alternate setting.
+#[derive(Debug, Clone, Copy)]
+pub struct Target<'a> {
+    alt_setting: u8,
+    name: Option<&'a [u8]>,
+    elements: &'a [u8],
+    elements_count: u32,
+}
+
+impl<'a> Target<'a> {
+    fn parse(bytes: &mut &'a [u8], index: u8) -> Result<Self, Error> {
+        let (prefix, rest) = split_at(bytes, TARGET_PREFIX_LEN)?;
+        if &prefix[..6] != TARGET_SIGNATURE {
+            return Err(Error::InvalidTargetSignature(index));
+        }
+        let alt_setting = prefix[6];
+        let named = read_u32(prefix, 7) != 0;
+        let name = &prefix[11..11 + TARGET_NAME_LEN];
+        let name = &name[..name.iter().position(|x| *x == 0).unwrap_or(name.len())];
+        let size = read_u32(prefix, 266) as usize;
+        let elements_count = read_u32(prefix, 270);
+
+        let (elements, rest) = split_at(rest, size)?;
+        let mut remaining = elements;
+        for _ in 0..elements_count {
+            let (element_prefix, data) = split_at(remaining, ELEMENT_PREFIX_LEN)?;
+            let (_, data) = split_at(data, read_u32(element_prefix, 4) as usize)?;
+            remaining = data;
+        }
+        if !remaining.is_empty() {
+            return Err(Error::InvalidTargetSize(index));
+        }
+
+        *bytes = rest;
+
+        Ok(Self {
+            alt_setting,
+            name: if named { Some(name) } else { None },
+            elements,
+            elements_count,
+        })
+    }
+
+    /// Returns the alternate setting of the interface targeted.
+    pub fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    /// Returns the name of the target if it has one.
+    pub fn name(&self) -> Option<&'a str> {
+        self.name.and_then(|name| core::str::from_utf8(name).ok())
+    }
+
+    /// Returns the image elements of the target: their address and their data.
+    pub fn elements(&self) -> Elements<'a> {
+        Elements {
+            bytes: self.elements,
+            remaining: self.elements_count,
+        }
+    }
+}
+
+/// Iterator over the image elements of a [`Target`].
+#[derive(Debug, Clone)]
+pub struct Elements<'a> {
+    bytes: &'a [u8],
+    remaining: u32,
+}
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = (u32, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        // the elements have been validated when parsing the target
+        let address = read_u32(self.bytes, 0);
+        let size = read_u32(self.bytes, 4) as usize;
+        let (data, rest) = self.bytes[ELEMENT_PREFIX_LEN..].split_at(size);
+        self.bytes = rest;
+
+        Some((address, data))
+    }
+}
+
+/// A target to write in a DfuSe file.
+#[cfg(any(feature = "std", test))]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[derive(Debug, Clone)]
+pub struct TargetImage<'a> {
+    /// Alternate setting of the interface targeted.
+    pub alt_setting: u8,
+    /// Name of the target.
+    pub name: Option<&'a str>,
+    /// Image elements: their address and their data.
+    pub elements: Vec<(u32, &'a [u8])>,
+}
+
+/// Writer of DfuSe files.
+#[cfg(any(feature = "std", test))]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[derive(Debug, Clone)]
+pub struct DfuseFileWriter<'a> {
+    targets: Vec<TargetImage<'a>>,
+    vendor_id: u16,
+    product_id: u16,
+    device: u16,
+}
+
+#[cfg(any(feature = "std", test))]
+impl<'a> Default for DfuseFileWriter<'a> {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            vendor_id: 0xffff,
+            product_id: 0xffff,
+            device: 0xffff,
+        }
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+impl<'a> DfuseFileWriter<'a> {
+    /// Create a writer without target for any device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write the vendor and product IDs of the device in the suffix.
+    pub fn with_ids(self, vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            vendor_id,
+            product_id,
+            ..self
+        }
+    }
+
+    /// Write the release number of the device (`bcdDevice`) in the suffix.
+    pub fn with_device(self, device: u16) -> Self {
+        Self { device, ..self }
+    }
+
+    /// Add a target.
+    pub fn with_target(mut self, target: TargetImage<'a>) -> Self {
+        self.targets.push(target);
+        self
+    }
+
+    /// Returns the DfuSe file.
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        use core::convert::TryFrom;
+
+        let targets_count = u8::try_from(self.targets.len())
+            .map_err(|_| Error::TooManyTargets(self.targets.len()))?;
+        let size = |len: usize| u32::try_from(len).map_err(|_| Error::TooLarge);
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(PREFIX_SIGNATURE);
+        bytes.push(PREFIX_VERSION);
+        // the image size is known once the targets are written
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.push(targets_count);
+
+        for target in &self.targets {
+            let name = target.name.unwrap_or_default().as_bytes();
+            if name.len() >= TARGET_NAME_LEN {
+                return Err(Error::NameTooLong(target.alt_setting));
+            }
+            let mut name_bytes = [0; TARGET_NAME_LEN];
+            name_bytes[..name.len()].copy_from_slice(name);
+            let elements_len = target
+                .elements
+                .iter()
+                .map(|(_, data)| ELEMENT_PREFIX_LEN + data.len())
+                .sum();
+
+            bytes.extend_from_slice(TARGET_SIGNATURE);
+            bytes.push(target.alt_setting);
+            bytes.extend_from_slice(&(target.name.is_some() as u32).to_le_bytes());
+            bytes.extend_from_slice(&name_bytes);
+            bytes.extend_from_slice(&size(elements_len)?.to_le_bytes());
+            bytes.extend_from_slice(&size(target.elements.len())?.to_le_bytes());
+
+            for (address, data) in &target.elements {
+                bytes.extend_from_slice(&address.to_le_bytes());
+                bytes.extend_from_slice(&size(data.len())?.to_le_bytes());
+                bytes.extend_from_slice(data);
+            }
+        }
+
+        let image_size = size(bytes.len())?;
+        bytes[6..10].copy_from_slice(&image_size.to_le_bytes());
+
+        let suffix =
+            crate::suffix::Suffix::new(self.vendor_id, self.product_id, self.device, DFUSE_VERSION);
+        let suffix = suffix.to_bytes(&bytes);
+        bytes.extend_from_slice(&suffix);
+
+        Ok(bytes)
+    }
+
+    /// Write the DfuSe file.
+    pub fn write<W: std::io::Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let bytes = self
+            .to_vec()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+        writer.write_all(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(alt_setting: u8, name: Option<&str>, elements: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (address, element) in elements {
+            data.extend_from_slice(&address.to_le_bytes());
+            data.extend_from_slice(&(element.len() as u32).to_le_bytes());
+            data.extend_from_slice(element);
+        }
+
+        let mut bytes = b"Target".to_vec();
+        bytes.push(alt_setting);
+        bytes.extend_from_slice(&(name.is_some() as u32).to_le_bytes());
+        let mut name_bytes = [0; TARGET_NAME_LEN];
+        let name = name.unwrap_or_default().as_bytes();
+        name_bytes[..name.len()].copy_from_slice(name);
+        bytes.extend_from_slice(&name_bytes);
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(elements.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&data);
+        bytes
+    }
+
+    fn file(targets: &[Vec<u8>]) -> Vec<u8> {
+        let size: usize = PREFIX_LEN + targets.iter().map(Vec::len).sum::<usize>();
+        let mut bytes = b"DfuSe\x01".to_vec();
+        bytes.extend_from_slice(&(size as u32).to_le_bytes());
+        bytes.push(targets.len() as u8);
+        for target in targets {
+            bytes.extend_from_slice(target);
+        }
+        bytes.extend_from_slice(&[0xff, 0xff, 0x11, 0xdf, 0x83, 0x04, 0x1a, 0x01]);
+        bytes.extend_from_slice(b"UFD\x10");
+        let crc = crate::suffix::crc32(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn parsing() {
+        let bytes = file(&[
+            target(
+                0,
+                Some("Internal Flash"),
+                &[(0x08000000, &[1, 2, 3]), (0x08004000, &[4])],
+            ),
+            target(1, None, &[(0x1fff7800, &[5; 16])]),
+        ]);
+        let file = DfuseFile::parse(&bytes).unwrap();
+
+        let targets = file.targets().collect::<Vec<_>>();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].alt_setting(), 0);
+        assert_eq!(targets[0].name(), Some("Internal Flash"));
+        assert_eq!(
+            targets[0].elements().collect::<Vec<_>>(),
+            vec![(0x08000000, &[1, 2, 3][..]), (0x08004000, &[4][..])]
+        );
+        assert_eq!(targets[1].name(), None);
+
+        let target = file.target(1).unwrap();
+        assert_eq!(
+            target.elements().collect::<Vec<_>>(),
+            vec![(0x1fff7800, &[5; 16][..])]
+        );
+        assert_eq!(file.target(2).unwrap_err(), Error::TargetNotFound(2));
+    }
+
+    #[test]
+    fn parsing_errors() {
+        let bytes = file(&[target(0, None, &[(0x08000000, &[1, 2, 3])])]);
+
+        assert_eq!(
+            DfuseFile::parse(&bytes[..bytes.len() - 1]).unwrap_err(),
+            Error::InvalidSuffix
+        );
+
+        let invalid = |at: usize, value: u8| {
+            let mut invalid = bytes.clone();
+            invalid[at] = value;
+            let len = invalid.len();
+            let crc = crate::suffix::crc32(&invalid[..len - 4]);
+            invalid[len - 4..].copy_from_slice(&crc.to_le_bytes());
+            DfuseFile::parse(&invalid).unwrap_err()
+        };
+
+        assert_eq!(invalid(0, b'X'), Error::InvalidSignature);
+        assert_eq!(invalid(PREFIX_LEN, b'X'), Error::InvalidTargetSignature(0));
+        // one more target announced than present
+        assert_eq!(invalid(10, 2), Error::UnexpectedEnd);
+        // element larger than the target
+        assert_eq!(
+            invalid(PREFIX_LEN + TARGET_PREFIX_LEN + 4, 4),
+            Error::UnexpectedEnd
+        );
+
+        let mut corrupted = bytes.clone();
+        corrupted[PREFIX_LEN + TARGET_PREFIX_LEN + 8] = 0xff;
+        assert!(matches!(
+            DfuseFile::parse(&corrupted).unwrap_err(),
+            Error::Suffix(crate::suffix::Error::InvalidCrc { .. })
+        ));
+    }
+
+    #[test]
+    fn writing() {
+        let bytes = DfuseFileWriter::new()
+            .with_ids(0x0483, 0xdf11)
+            .with_target(TargetImage {
+                alt_setting: 0,
+                name: Some("Internal Flash"),
+                elements: vec![(0x08000000, &[1, 2, 3]), (0x08004000, &[4])],
+            })
+            .with_target(TargetImage {
+                alt_setting: 1,
+                name: None,
+                elements: vec![(0x1fff7800, &[5; 16])],
+            })
+            .to_vec()
+            .unwrap();
+
+        assert_eq!(
+            bytes[..bytes.len() - 4],
+            file(&[
+                target(
+                    0,
+                    Some("Internal Flash"),
+                    &[(0x08000000, &[1, 2, 3]), (0x08004000, &[4])],
+                ),
+                target(1, None, &[(0x1fff7800, &[5; 16])]),
+            ])[..bytes.len() - 4]
+        );
+        assert_eq!(
+            bytes[bytes.len() - 4..],
+            crate::suffix::crc32(&bytes[..bytes.len() - 4]).to_le_bytes()
+        );
+
+        let file = DfuseFile::parse(&bytes).unwrap();
+        assert_eq!(
+            file.target(0).unwrap().elements().collect::<Vec<_>>(),
+            vec![(0x08000000, &[1, 2, 3][..]), (0x08004000, &[4][..])]
+        );
+    }
+}
@@ -1,15 +1,102 @@
 use super::*;
 
 const REQUEST_TYPE: u8 = 0b00100001;
-const DFU_DNLOAD: u8 = 1;
+pub(crate) const DFU_DNLOAD: u8 = 1;
+
+/// Check that the range `[start, end)` is covered by the memory layout and that its pages can be
+/// written.
+///
+/// The pages that cannot be erased (e.g. option bytes) are written without being erased.
+pub(crate) fn check_memory_layout(
+    memory_layout: &[memory_layout::Page],
+    start: u32,
+    end: u32,
+) -> Result<(), Error> {
+    if let Some(address) = memory_layout::uncovered(memory_layout, start, end) {
+        return Err(Error::AddressOutOfRange(address));
+    }
+
+    if let Some(page) = memory_layout::pages_overlapping(memory_layout, start, end)
+        .find(|page| !page.permissions.writable)
+    {
+        return Err(Error::NotWritable(page.address));
+    }
+
+    Ok(())
+}
+
+/// A segment of data to download: its address and its length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Address of the segment.
+    pub address: u32,
+    /// Length of the segment.
+    pub length: u32,
+}
+
+impl Segment {
+    /// Returns the end address (exclusive) of the segment.
+    pub fn end(&self) -> Result<u32, Error> {
+        self.address
+            .checked_add(self.length)
+            .ok_or(Error::NoSpaceLeft)
+    }
+}
 
 /// Starting point to download a firmware into a device.
 #[must_use]
 pub struct Start<'dfu, IO: DfuIo> {
     pub(crate) dfu: &'dfu DfuSansIo<IO>,
-    pub(crate) memory_layout: &'dfu memory_layout::mem,
+    pub(crate) memory_layout: &'dfu [memory_layout::Page],
     pub(crate) address: u32,
     pub(crate) end_pos: u32,
+    pub(crate) segments: &'dfu [Segment],
+    pub(crate) recovered: bool,
+}
+
+impl<'dfu, IO: DfuIo> Start<'dfu, IO> {
+    /// Check that the device in DFU mode can receive the data.
+    ///
+    /// A device running its application may speak another protocol and may not describe its
+    /// memory layout until it is switched to DFU mode.
+    fn check(&self) -> Result<(), Error> {
+        match self.dfu.protocol {
+            Protocol::Dfu if !self.segments.is_empty() => Err(Error::AddressingNotSupported),
+            Protocol::Dfu => Ok(()),
+            Protocol::DfuSe => {
+                self.dfu.check_command(get_commands::COMMAND_ERASE)?;
+                self.dfu.check_command(get_commands::COMMAND_SET_ADDRESS)?;
+                check_memory_layout(self.memory_layout, self.address, self.end_pos)?;
+                for segment in self.segments {
+                    check_memory_layout(self.memory_layout, segment.address, segment.end()?)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    fn into_loop(self) -> DownloadLoop<'dfu, IO> {
+        DownloadLoop {
+            dfu: self.dfu,
+            memory_layout: self.memory_layout,
+            segments: self.segments,
+            end_pos: self.end_pos,
+            copied_pos: self.address,
+            erased_pos: self.address,
+            address_set: false,
+            // DfuSe writes to ((wBlockNum - 2) * wTransferSize) + address pointer.
+            block_num: match self.dfu.protocol {
+                Protocol::Dfu => 0,
+                Protocol::DfuSe => 2,
+            },
+            manifested: false,
+            detach: false,
+            abort: false,
+            clear: false,
+            retries: 0,
+        }
+    }
 }
 
 impl<'dfu, IO: DfuIo> ChainedCommand for Start<'dfu, IO> {
@@ -25,23 +112,39 @@ impl<'dfu, IO: DfuIo> ChainedCommand for Start<'dfu, IO> {
             index: _,
         }: Self::Arg,
     ) -> Self::Into {
-        // TODO startup can be in AppIdle in which case the Detach-Attach process needs to be done
-        if state == State::DfuIdle {
-            Ok(DownloadLoop {
-                dfu: self.dfu,
-                memory_layout: self.memory_layout,
-                end_pos: self.end_pos,
-                copied_pos: self.address,
-                erased_pos: self.address,
-                address_set: false,
-                block_num: 2,
-                eof: false,
-            })
-        } else {
-            Err(Error::InvalidState {
+        match state {
+            State::DfuIdle => {
+                self.check()?;
+                Ok(self.into_loop())
+            }
+            State::AppIdle => Ok(DownloadLoop {
+                detach: true,
+                ..self.into_loop()
+            }),
+            _ if abort::interrupted(state) && !self.recovered => Ok(DownloadLoop {
+                abort: true,
+                ..self.into_loop()
+            }),
+            _ => Err(Error::InvalidState {
                 got: state,
                 expected: State::DfuIdle,
-            })
+            }),
+        }
+    }
+
+    fn chain_error(
+        self,
+        get_status::GetStatusMessage { state, .. }: Self::Arg,
+        error: Error,
+    ) -> Result<Self::Into, Error> {
+        // the error has been left by a previous session
+        if state == State::DfuError && !self.recovered {
+            Ok(Ok(DownloadLoop {
+                clear: true,
+                ..self.into_loop()
+            }))
+        } else {
+            Err(error)
         }
     }
 }
@@ -50,72 +153,202 @@ impl<'dfu, IO: DfuIo> ChainedCommand for Start<'dfu, IO> {
 #[must_use]
 pub struct DownloadLoop<'dfu, IO: DfuIo> {
     dfu: &'dfu DfuSansIo<IO>,
-    memory_layout: &'dfu memory_layout::mem,
+    memory_layout: &'dfu [memory_layout::Page],
+    segments: &'dfu [Segment],
     end_pos: u32,
     copied_pos: u32,
     erased_pos: u32,
     address_set: bool,
     block_num: u16,
-    eof: bool,
+    manifested: bool,
+    detach: bool,
+    abort: bool,
+    clear: bool,
+    retries: u16,
 }
 
 impl<'dfu, IO: DfuIo> DownloadLoop<'dfu, IO> {
     /// Get the next step in the download loop.
+    ///
+    /// A device running its application (`appIDLE`) is detached and the download ends there: it
+    /// must be started again once the device re-enumerated in DFU mode. The transfer of an
+    /// interrupted session is aborted first (see [`Step::Abort`]) and the error left by a previous
+    /// session is cleared (see [`Step::ClearStatus`]).
+    ///
+    /// With DfuSe, only the erasable pages overlapping the data of the current segment that have
+    /// not already been erased are erased before writing the segment. With plain DFU, the erase and
+    /// set address steps are skipped and the data is streamed directly.
+    ///
+    /// Once all the data has been written, plain DFU ends with [`Step::Manifest`]. DfuSe ends
+    /// with [`Step::Break`] as the zero-length download makes the device leave the DFU mode (see
+    /// [`DfuSansIo::leave`]).
     pub fn next(self) -> Step<'dfu, IO> {
-        if self.eof {
-            Step::Break
-        } else if self.erased_pos < self.end_pos {
+        let dfuse = self.dfu.protocol == Protocol::DfuSe;
+
+        if self.detach {
+            return Step::Detach(self.dfu.detach());
+        }
+
+        if self.abort {
+            return Step::Abort(abort::Abort::start_over(self.dfu, self.start_over()));
+        }
+
+        if self.clear {
+            return Step::ClearStatus(get_status::ClearStatus {
+                dfu: self.dfu,
+                chained_command: get_status::GetStatus {
+                    dfu: self.dfu,
+                    chained_command: self.start_over(),
+                },
+            });
+        }
+
+        if self.manifested {
+            return Step::Break;
+        }
+
+        if self.copied_pos >= self.end_pos {
+            let (segment, segments) = match self.segments.split_first() {
+                Some(x) => x,
+                None if dfuse => return Step::Break,
+                None => {
+                    return Step::Manifest(Manifest {
+                        dfu: self.dfu,
+                        memory_layout: self.memory_layout,
+                        end_pos: self.end_pos,
+                        block_num: self.block_num,
+                    })
+                }
+            };
+
+            return DownloadLoop {
+                dfu: self.dfu,
+                memory_layout: self.memory_layout,
+                segments,
+                end_pos: segment.address.saturating_add(segment.length),
+                copied_pos: segment.address,
+                erased_pos: self.erased_pos,
+                address_set: false,
+                block_num: self.block_num,
+                manifested: false,
+                detach: false,
+                abort: false,
+                clear: false,
+                retries: 0,
+            }
+            .next();
+        }
+
+        let page_to_erase = if dfuse {
+            memory_layout::pages_overlapping(
+                self.memory_layout,
+                self.erased_pos.max(self.copied_pos),
+                self.end_pos,
+            )
+            .filter(|page| page.permissions.erasable)
+            .min_by_key(|page| page.address)
+        } else {
+            None
+        };
+
+        if let Some(page) = page_to_erase {
             Step::Erase(ErasePage {
                 dfu: self.dfu,
                 memory_layout: self.memory_layout,
+                segments: self.segments,
+                page: *page,
                 end_pos: self.end_pos,
                 copied_pos: self.copied_pos,
-                erased_pos: self.erased_pos,
                 block_num: self.block_num,
+                retries: self.retries,
             })
-        } else if !self.address_set {
+        } else if dfuse && !self.address_set {
             Step::SetAddress(SetAddress {
                 dfu: self.dfu,
                 memory_layout: self.memory_layout,
+                segments: self.segments,
                 end_pos: self.end_pos,
                 copied_pos: self.copied_pos,
                 erased_pos: self.erased_pos,
-                block_num: self.block_num,
+                retries: self.retries,
             })
         } else {
             Step::DownloadChunk(DownloadChunk {
                 dfu: self.dfu,
                 memory_layout: self.memory_layout,
+                segments: self.segments,
                 end_pos: self.end_pos,
                 copied_pos: self.copied_pos,
                 erased_pos: self.erased_pos,
                 block_num: self.block_num,
+                retries: self.retries,
             })
         }
     }
+
+    /// Start the download over once the device has been brought back to `dfuIDLE`.
+    fn start_over(&self) -> Start<'dfu, IO> {
+        Start {
+            dfu: self.dfu,
+            memory_layout: self.memory_layout,
+            address: self.copied_pos,
+            end_pos: self.end_pos,
+            segments: self.segments,
+            recovered: true,
+        }
+    }
+
+    /// Returns this loop if the recovery policy allows the retry.
+    ///
+    /// Only DfuSe can retry as the address pointer is set again: a plain DFU device back in
+    /// `dfuIDLE` would write the block at the start of the firmware.
+    fn retry(self) -> Option<Self> {
+        (self.dfu.protocol == Protocol::DfuSe
+            && self.retries <= u16::from(self.dfu.recovery_policy.retries()))
+        .then_some(self)
+    }
 }
 
 /// Download step in the loop.
 #[allow(missing_docs)]
 pub enum Step<'dfu, IO: DfuIo> {
     Break,
+    /// The device is in run-time mode and must be switched to DFU mode: the download must be
+    /// started again once the device has been opened again (see [`DfuIo::reconnect`] and
+    /// [`DfuSansIo::replace_io`]).
+    Detach(detach::Detach<'dfu, IO, detach::Attach<'dfu, IO, ()>>),
+    /// A previous transfer must be aborted before downloading.
+    Abort(abort::Abort<'dfu, IO, get_status::GetStatus<'dfu, IO, Start<'dfu, IO>>>),
+    /// The device is in error after a previous session: its status must be cleared.
+    ClearStatus(
+        get_status::ClearStatus<'dfu, IO, get_status::GetStatus<'dfu, IO, Start<'dfu, IO>>>,
+    ),
     Erase(ErasePage<'dfu, IO>),
     SetAddress(SetAddress<'dfu, IO>),
     DownloadChunk(DownloadChunk<'dfu, IO>),
+    /// All the data has been written: the device must be told to manifest the firmware.
+    Manifest(Manifest<'dfu, IO>),
 }
 
 /// Erase a memory page.
 #[must_use]
 pub struct ErasePage<'dfu, IO: DfuIo> {
     dfu: &'dfu DfuSansIo<IO>,
-    memory_layout: &'dfu memory_layout::mem,
+    memory_layout: &'dfu [memory_layout::Page],
+    segments: &'dfu [Segment],
+    page: memory_layout::Page,
     end_pos: u32,
     copied_pos: u32,
-    erased_pos: u32,
     block_num: u16,
+    retries: u16,
 }
 
 impl<'dfu, IO: DfuIo> ErasePage<'dfu, IO> {
+    /// Returns the page that will be erased.
+    pub fn page(&self) -> memory_layout::Page {
+        self.page
+    }
+
     /// Erase a memory page.
     pub fn erase(
         self,
@@ -126,31 +359,55 @@ impl<'dfu, IO: DfuIo> ErasePage<'dfu, IO> {
         ),
         IO::Error,
     > {
-        let (page_size, rest_memory_layout) =
-            self.memory_layout.split_first().ok_or(Error::NoSpaceLeft)?;
+        let page = self.page;
+
+        if !page.permissions.erasable {
+            return Err(Error::NotErasable(page.address).into());
+        }
 
+        let retry = DownloadLoop {
+            dfu: self.dfu,
+            memory_layout: self.memory_layout,
+            segments: self.segments,
+            end_pos: self.end_pos,
+            copied_pos: self.copied_pos,
+            erased_pos: page.address,
+            block_num: self.block_num,
+            address_set: false,
+            manifested: false,
+            detach: false,
+            abort: false,
+            clear: false,
+            retries: self.retries + 1,
+        };
         let next = get_status::WaitState::new(
             self.dfu,
             State::DfuDnloadIdle,
             DownloadLoop {
                 dfu: self.dfu,
-                memory_layout: rest_memory_layout,
+                memory_layout: self.memory_layout,
+                segments: self.segments,
                 end_pos: self.end_pos,
                 copied_pos: self.copied_pos,
-                erased_pos: self
-                    .erased_pos
-                    .checked_add(*page_size)
+                erased_pos: page
+                    .address
+                    .checked_add(page.size)
                     .ok_or(Error::EraseLimitReached)?,
                 block_num: self.block_num,
                 address_set: false,
-                eof: false,
+                manifested: false,
+                detach: false,
+                abort: false,
+                clear: false,
+                retries: 0,
             },
-        );
+        )
+        .with_retry(retry.retry());
         let res = self.dfu.io.write_control(
             REQUEST_TYPE,
             DFU_DNLOAD,
             0,
-            &<[u8; 5]>::from(DownloadCommandErase(self.erased_pos)),
+            &<[u8; 5]>::from(DownloadCommandErase(page.address)),
         )?;
 
         Ok((next, res))
@@ -161,11 +418,12 @@ impl<'dfu, IO: DfuIo> ErasePage<'dfu, IO> {
 #[must_use]
 pub struct SetAddress<'dfu, IO: DfuIo> {
     dfu: &'dfu DfuSansIo<IO>,
-    memory_layout: &'dfu memory_layout::mem,
+    memory_layout: &'dfu [memory_layout::Page],
+    segments: &'dfu [Segment],
     end_pos: u32,
     copied_pos: u32,
     erased_pos: u32,
-    block_num: u16,
+    retries: u16,
 }
 
 impl<'dfu, IO: DfuIo> SetAddress<'dfu, IO> {
@@ -179,20 +437,42 @@ impl<'dfu, IO: DfuIo> SetAddress<'dfu, IO> {
         ),
         IO::Error,
     > {
+        let retry = DownloadLoop {
+            dfu: self.dfu,
+            memory_layout: self.memory_layout,
+            segments: self.segments,
+            end_pos: self.end_pos,
+            copied_pos: self.copied_pos,
+            erased_pos: self.erased_pos,
+            block_num: 2,
+            address_set: false,
+            manifested: false,
+            detach: false,
+            abort: false,
+            clear: false,
+            retries: self.retries + 1,
+        };
         let next = get_status::WaitState::new(
             self.dfu,
             State::DfuDnloadIdle,
             DownloadLoop {
                 dfu: self.dfu,
                 memory_layout: self.memory_layout,
+                segments: self.segments,
                 end_pos: self.end_pos,
                 copied_pos: self.copied_pos,
                 erased_pos: self.erased_pos,
-                block_num: self.block_num,
+                // the block number is relative to the address pointer
+                block_num: 2,
                 address_set: true,
-                eof: false,
+                manifested: false,
+                detach: false,
+                abort: false,
+                clear: false,
+                retries: self.retries,
             },
-        );
+        )
+        .with_retry(retry.retry());
         let res = self.dfu.io.write_control(
             REQUEST_TYPE,
             DFU_DNLOAD,
@@ -208,15 +488,28 @@ impl<'dfu, IO: DfuIo> SetAddress<'dfu, IO> {
 #[must_use]
 pub struct DownloadChunk<'dfu, IO: DfuIo> {
     dfu: &'dfu DfuSansIo<IO>,
-    memory_layout: &'dfu memory_layout::mem,
+    memory_layout: &'dfu [memory_layout::Page],
+    segments: &'dfu [Segment],
     end_pos: u32,
     copied_pos: u32,
     erased_pos: u32,
     block_num: u16,
+    retries: u16,
 }
 
 impl<'dfu, IO: DfuIo> DownloadChunk<'dfu, IO> {
+    /// Returns the address where the chunk will be written.
+    pub fn address(&self) -> u32 {
+        self.copied_pos
+    }
+
     /// Download a chunk of data into the device.
+    ///
+    /// When a DfuSe device reports an error and the recovery policy allows a retry, the same chunk
+    /// is requested again at the same address.
+    ///
+    /// The chunk is truncated to the transfer size and to the end of the current segment. An empty
+    /// chunk is an error as the end of the firmware has not been reached.
     pub fn download(
         self,
         bytes: &[u8],
@@ -234,14 +527,40 @@ impl<'dfu, IO: DfuIo> DownloadChunk<'dfu, IO> {
                 got: bytes.len(),
                 expected: u32::MAX as usize,
             })?
-            .min(self.dfu.io.functional_descriptor().transfer_size as u32);
+            .min(self.dfu.io.functional_descriptor().transfer_size as u32)
+            .min(self.end_pos.saturating_sub(self.copied_pos));
+
+        if len == 0 {
+            return Err(Error::UnexpectedEndOfData {
+                got: self.copied_pos,
+                expected: self.end_pos,
+            }
+            .into());
+        }
 
+        // with DfuSe, the address pointer is set again before retrying
+        let retry = DownloadLoop {
+            dfu: self.dfu,
+            memory_layout: self.memory_layout,
+            segments: self.segments,
+            end_pos: self.end_pos,
+            copied_pos: self.copied_pos,
+            erased_pos: self.erased_pos,
+            block_num: self.block_num,
+            address_set: false,
+            manifested: false,
+            detach: false,
+            abort: false,
+            clear: false,
+            retries: self.retries + 1,
+        };
         let next = get_status::WaitState::new(
             self.dfu,
             State::DfuDnloadIdle,
             DownloadLoop {
                 dfu: self.dfu,
                 memory_layout: self.memory_layout,
+                segments: self.segments,
                 end_pos: self.end_pos,
                 copied_pos: self
                     .copied_pos
@@ -253,9 +572,14 @@ impl<'dfu, IO: DfuIo> DownloadChunk<'dfu, IO> {
                     .checked_add(1)
                     .ok_or(Error::MaximumChunksExceeded)?,
                 address_set: true,
-                eof: bytes.is_empty(),
+                manifested: false,
+                detach: false,
+                abort: false,
+                clear: false,
+                retries: 0,
             },
-        );
+        )
+        .with_retry(retry.retry());
         let res = self.dfu.io.write_control(
             REQUEST_TYPE,
             DFU_DNLOAD,
@@ -267,6 +591,58 @@ impl<'dfu, IO: DfuIo> DownloadChunk<'dfu, IO> {
     }
 }
 
+/// Send the zero-length download that ends the transfer and triggers the manifestation.
+#[must_use]
+pub struct Manifest<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+    memory_layout: &'dfu [memory_layout::Page],
+    end_pos: u32,
+    block_num: u16,
+}
+
+impl<'dfu, IO: DfuIo> Manifest<'dfu, IO> {
+    /// Send the zero-length download to the device.
+    ///
+    /// The device then goes through `dfuMANIFEST-SYNC` and `dfuMANIFEST` until it is back to
+    /// `dfuIDLE`. A device that is not manifestation tolerant stops in `dfuMANIFEST-WAIT-RESET`
+    /// instead, in which case [`get_status::Step::ManifestWaitReset`] is returned.
+    pub fn manifest(
+        self,
+    ) -> Result<
+        (
+            get_status::WaitState<'dfu, IO, DownloadLoop<'dfu, IO>>,
+            IO::Write,
+        ),
+        IO::Error,
+    > {
+        let next = get_status::WaitState::new(
+            self.dfu,
+            State::DfuIdle,
+            DownloadLoop {
+                dfu: self.dfu,
+                memory_layout: self.memory_layout,
+                segments: &[],
+                end_pos: self.end_pos,
+                copied_pos: self.end_pos,
+                erased_pos: self.end_pos,
+                address_set: true,
+                block_num: self.block_num,
+                manifested: true,
+                detach: false,
+                abort: false,
+                clear: false,
+                retries: 0,
+            },
+        );
+        let res = self
+            .dfu
+            .io
+            .write_control(REQUEST_TYPE, DFU_DNLOAD, self.block_num, &[])?;
+
+        Ok((next, res))
+    }
+}
+
 /// Command to erase.
 #[derive(Debug, Clone, Copy)]
 pub struct DownloadCommandErase(u32);
@@ -280,9 +656,29 @@ impl From<DownloadCommandErase> for [u8; 5] {
     }
 }
 
+/// Command to erase the whole memory.
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadCommandMassErase;
+
+impl From<DownloadCommandMassErase> for [u8; 1] {
+    fn from(_: DownloadCommandMassErase) -> Self {
+        [0x41]
+    }
+}
+
+/// Command to remove the read protection.
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadCommandReadUnprotect;
+
+impl From<DownloadCommandReadUnprotect> for [u8; 1] {
+    fn from(_: DownloadCommandReadUnprotect) -> Self {
+        [0x92]
+    }
+}
+
 /// Command to set address to download.
 #[derive(Debug, Clone, Copy)]
-pub struct DownloadCommandSetAddress(u32);
+pub struct DownloadCommandSetAddress(pub(crate) u32);
 
 impl From<DownloadCommandSetAddress> for [u8; 5] {
     fn from(command: DownloadCommandSetAddress) -> Self {
@@ -292,3 +688,228 @@ impl From<DownloadCommandSetAddress> for [u8; 5] {
         buffer
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::TryFrom;
+    use mock::{Device, Request};
+    use std::vec::Vec;
+
+    fn wait<T>(cmd: get_status::WaitState<'_, &Device, T>) -> T {
+        let mut buffer = [0; 6];
+        let mut cmd = cmd;
+        loop {
+            cmd = match cmd.next() {
+                get_status::Step::Break(cmd) => return cmd,
+                get_status::Step::Wait(cmd, _) => {
+                    let (cmd, n) = cmd.get_status(&mut buffer).unwrap();
+                    cmd.chain(&buffer[..n]).unwrap()
+                }
+                _ => panic!("unexpected step"),
+            };
+        }
+    }
+
+    #[test]
+    fn erase_planner() {
+        let device = Device::new(Protocol::DfuSe);
+        let dfu = DfuSansIo::new(&device, mock::ADDRESS);
+        // the pages 0x08000800 and 0x08000c00 are between the segments and the page 0x08000400
+        // is shared by the end of the first segment and the second segment
+        let segments = [
+            Segment {
+                address: 0x08000100,
+                length: 0x400,
+            },
+            Segment {
+                address: 0x08000600,
+                length: 0x100,
+            },
+            Segment {
+                address: 0x08001800,
+                length: 0x100,
+            },
+        ];
+
+        let mut buffer = [0; 6];
+        let cmd = dfu.download_segments(&segments).unwrap();
+        let (cmd, n) = cmd.get_status(&mut buffer).unwrap();
+        let mut download_loop = cmd.chain(&buffer[..n]).unwrap().unwrap();
+        let mut pages = Vec::new();
+        loop {
+            download_loop = match download_loop.next() {
+                Step::Break => break,
+                Step::Erase(cmd) => {
+                    pages.push(cmd.page().address);
+                    wait(cmd.erase().unwrap().0)
+                }
+                Step::SetAddress(cmd) => wait(cmd.set_address().unwrap().0),
+                Step::DownloadChunk(cmd) => wait(cmd.download(&[0x42; 0x100]).unwrap().0),
+                _ => panic!("unexpected step"),
+            };
+        }
+
+        assert_eq!(pages, vec![0x08000000, 0x08000400, 0x08001000]);
+    }
+
+    #[test]
+    fn manifest() {
+        for (manifestation_tolerant, will_detach) in [(true, false), (false, true), (false, false)]
+        {
+            let mut device = Device::new(Protocol::Dfu);
+            device.functional_descriptor.manifestation_tolerant = manifestation_tolerant;
+            device.functional_descriptor.will_detach = will_detach;
+            let mut dfu = sync::DfuSync::new(&device, 0);
+            dfu.download_from_slice(&[0x42; 300]).unwrap();
+
+            let mut requests = vec![
+                Request::Dnload(0, 256),
+                Request::Dnload(1, 44),
+                Request::Dnload(2, 0),
+            ];
+            if !manifestation_tolerant && !will_detach {
+                requests.push(Request::UsbReset);
+            }
+            // the device is opened again once it left the bus
+            if !manifestation_tolerant {
+                requests.push(Request::Reconnect);
+            }
+            assert_eq!(device.take_requests(), requests);
+            assert_eq!(device.state.get(), State::DfuIdle);
+        }
+    }
+
+    #[test]
+    fn start() {
+        // the device describes its memory layout once switched to DFU mode
+        let device = Device::runtime(Protocol::DfuSe);
+        let firmware = (0..0x800).map(|i| i as u8).collect::<Vec<_>>();
+        let mut dfu = sync::DfuSync::new(&device, mock::ADDRESS);
+        dfu.download_from_slice(&firmware).unwrap();
+        assert_eq!(
+            device.take_requests()[..6],
+            [
+                Request::Detach,
+                Request::UsbReset,
+                Request::Reconnect,
+                Request::Erase(0x08000000),
+                Request::Erase(0x08000400),
+                Request::SetAddress(0x08000000),
+            ]
+        );
+        assert_eq!(*device.memory.borrow(), firmware);
+
+        // the error left by a previous session is cleared
+        let device = Device::new(Protocol::Dfu);
+        device.state.set(State::DfuError);
+        let mut dfu = sync::DfuSync::new(&device, 0);
+        dfu.download_from_slice(&firmware).unwrap();
+        assert_eq!(
+            device.take_requests()[..2],
+            [Request::ClrStatus, Request::Dnload(0, 256)]
+        );
+    }
+
+    #[test]
+    fn recovery() {
+        let firmware = (0..0x500).map(|i| i as u8).collect::<Vec<_>>();
+
+        // the address pointer is set again before writing the chunk again
+        let device = Device::new(Protocol::DfuSe);
+        device.errors.borrow_mut().push_back((0x03, 0));
+        let mut dfu = sync::DfuSync::new(&device, mock::ADDRESS)
+            .with_recovery_policy(RecoveryPolicy::Retry(1));
+        dfu.download_from_slice(&firmware).unwrap();
+        assert_eq!(
+            device.take_requests()[..7],
+            [
+                Request::Erase(0x08000000),
+                Request::Erase(0x08000400),
+                Request::SetAddress(0x08000000),
+                Request::Dnload(2, 256),
+                Request::ClrStatus,
+                Request::SetAddress(0x08000000),
+                Request::Dnload(2, 256),
+            ]
+        );
+        assert_eq!(*device.memory.borrow(), firmware);
+
+        // the retries of the same chunk are limited
+        device.errors.borrow_mut().extend([(0x03, 0), (0x03, 0)]);
+        assert!(matches!(
+            dfu.download_from_slice(&firmware),
+            Err(mock::MockError::Dfu(Error::StatusError {
+                status: Status::ErrWrite,
+                ..
+            }))
+        ));
+
+        for (protocol, recovery_policy) in [
+            (Protocol::DfuSe, RecoveryPolicy::Abort),
+            (Protocol::Dfu, RecoveryPolicy::Retry(1)),
+        ] {
+            let device = Device::new(protocol);
+            device.errors.borrow_mut().push_back((0x03, 0));
+            let mut dfu =
+                sync::DfuSync::new(&device, mock::ADDRESS).with_recovery_policy(recovery_policy);
+            assert!(matches!(
+                dfu.download_from_slice(&firmware),
+                Err(mock::MockError::Dfu(Error::StatusError {
+                    status: Status::ErrWrite,
+                    ..
+                }))
+            ));
+            assert!(!device.take_requests().contains(&Request::ClrStatus));
+        }
+    }
+
+    #[test]
+    fn permissions() {
+        let memory_map =
+            memory_layout::DfuseMemory::try_from("@Flash/0x08000000/01*001Kg,01*001Ke,01*001Ka")
+                .unwrap()
+                .memory_map();
+
+        assert!(check_memory_layout(&memory_map, 0x08000000, 0x08000800).is_ok());
+        assert!(matches!(
+            check_memory_layout(&memory_map, 0x08000400, 0x08000c00),
+            Err(Error::NotWritable(0x08000800))
+        ));
+        assert!(matches!(
+            check_memory_layout(&memory_map, 0x08000400, 0x08001000),
+            Err(Error::AddressOutOfRange(0x08000c00))
+        ));
+
+        // the page that cannot be erased is written without being erased
+        let mut device = Device::new(Protocol::DfuSe);
+        device.memory_map = memory_map.clone();
+        let mut dfu = sync::DfuSync::new(&device, mock::ADDRESS);
+        dfu.download_from_slice(&[0x42; 0x800]).unwrap();
+        assert_eq!(
+            device
+                .take_requests()
+                .into_iter()
+                .filter(|request| matches!(request, Request::Erase(_)))
+                .collect::<Vec<_>>(),
+            vec![Request::Erase(0x08000000)]
+        );
+
+        let dfu = DfuSansIo::new(&device, mock::ADDRESS);
+        let erase = ErasePage {
+            dfu: &dfu,
+            memory_layout: &memory_map,
+            segments: &[],
+            page: memory_map[1],
+            end_pos: 0x08000800,
+            copied_pos: 0x08000400,
+            block_num: 2,
+            retries: 0,
+        };
+        assert!(matches!(
+            erase.erase(),
+            Err(mock::MockError::Dfu(Error::NotErasable(0x08000400)))
+        ));
+        assert_eq!(device.take_requests(), vec![]);
+    }
+}
@@ -93,4 +93,15 @@ impl FunctionalDescriptor {
             dfu_version,
         }))
     }
+
+    /// Returns the protocol of the device according to its DFU version.
+    ///
+    /// A `bcdDFUVersion` of `0x011a` indicates the STM32 DfuSe extensions.
+    pub fn protocol(&self) -> crate::Protocol {
+        if self.dfu_version == (0x01, 0x1a) {
+            crate::Protocol::DfuSe
+        } else {
+            crate::Protocol::Dfu
+        }
+    }
 }
@@ -0,0 +1,153 @@
+use super::*;
+
+const REQUEST_TYPE: u8 = 0b00100001;
+const DFU_UPLOAD: u8 = 2;
+
+/// Get command.
+pub const COMMAND_GET: u8 = 0x00;
+/// Set Address Pointer command.
+pub const COMMAND_SET_ADDRESS: u8 = 0x21;
+/// Erase command.
+pub const COMMAND_ERASE: u8 = 0x41;
+/// Read Unprotect command.
+pub const COMMAND_READ_UNPROTECT: u8 = 0x92;
+
+/// Set of the DfuSe commands supported by a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Commands([u32; 8]);
+
+impl Commands {
+    /// Read the set of commands from the bytes returned by the device.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut commands = Self::default();
+        for command in bytes {
+            commands.0[(command / 32) as usize] |= 1 << (command % 32);
+        }
+        commands
+    }
+
+    /// Returns `true` if the command is supported.
+    pub fn contains(&self, command: u8) -> bool {
+        self.0[(command / 32) as usize] & (1 << (command % 32)) > 0
+    }
+
+    /// Returns `true` if the Set Address Pointer command is supported.
+    pub fn set_address(&self) -> bool {
+        self.contains(COMMAND_SET_ADDRESS)
+    }
+
+    /// Returns `true` if the Erase command is supported.
+    pub fn erase(&self) -> bool {
+        self.contains(COMMAND_ERASE)
+    }
+
+    /// Returns `true` if the Read Unprotect command is supported.
+    pub fn read_unprotect(&self) -> bool {
+        self.contains(COMMAND_READ_UNPROTECT)
+    }
+
+    /// Iterate over the supported commands.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(move |command| self.contains(*command))
+    }
+
+    /// Iterate over the supported commands that are not defined by DfuSe.
+    pub fn vendor(&self) -> impl Iterator<Item = u8> + '_ {
+        self.iter().filter(|command| {
+            !matches!(
+                *command,
+                COMMAND_GET | COMMAND_SET_ADDRESS | COMMAND_ERASE | COMMAND_READ_UNPROTECT
+            )
+        })
+    }
+}
+
+/// Starting point to query the commands supported by a DfuSe device.
+#[must_use]
+pub struct Start<'dfu, IO: DfuIo> {
+    pub(crate) dfu: &'dfu DfuSansIo<IO>,
+    pub(crate) aborted: bool,
+}
+
+impl<'dfu, IO: DfuIo> ChainedCommand for Start<'dfu, IO> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<abort::Step<'dfu, IO, Self, UploadCommands<'dfu, IO>>, Error>;
+
+    fn chain(
+        self,
+        get_status::GetStatusMessage {
+            status: _,
+            poll_timeout: _,
+            state,
+            index: _,
+        }: Self::Arg,
+    ) -> Self::Into {
+        let dfu = self.dfu;
+
+        abort::Step::new(
+            dfu,
+            state,
+            self.aborted,
+            || UploadCommands { dfu },
+            || Start { dfu, aborted: true },
+        )
+    }
+}
+
+/// Read the commands supported by the device.
+#[must_use]
+pub struct UploadCommands<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+}
+
+impl<'dfu, IO: DfuIo> UploadCommands<'dfu, IO> {
+    /// Read the commands supported by the device into the buffer.
+    ///
+    /// The Get command is an upload of the block 0 (see AN3156): the first byte returned is the
+    /// Get command itself, followed by the other commands supported.
+    pub fn upload(self, buffer: &mut [u8]) -> Result<(UploadCommandsRecv, IO::Read), IO::Error> {
+        let res = self
+            .dfu
+            .io
+            .read_control(REQUEST_TYPE, DFU_UPLOAD, 0, buffer)?;
+
+        Ok((UploadCommandsRecv, res))
+    }
+}
+
+/// Parse the commands after getting them from the device.
+#[must_use]
+pub struct UploadCommandsRecv;
+
+impl UploadCommandsRecv {
+    /// Parse the commands returned by the device.
+    pub fn chain(self, bytes: &[u8]) -> Result<Commands, Error> {
+        match bytes.first().copied() {
+            Some(COMMAND_GET) => Ok(Commands::from_bytes(bytes)),
+            Some(_) => Err(Error::UnsupportedCommand(COMMAND_GET)),
+            None => Err(Error::ResponseTooShort {
+                got: 0,
+                expected: 1,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::prelude::v1::*;
+
+    #[test]
+    fn parsing_commands() {
+        let commands = Commands::from_bytes(&[0x00, 0x21, 0x41, 0x92, 0xff]);
+        assert!(commands.set_address());
+        assert!(commands.erase());
+        assert!(commands.read_unprotect());
+        assert_eq!(commands.vendor().collect::<Vec<_>>(), [0xff]);
+
+        let commands = Commands::from_bytes(&[0x00, 0x21]);
+        assert!(!commands.erase());
+        assert_eq!(commands.iter().collect::<Vec<_>>(), [0x00, 0x21]);
+    }
+}
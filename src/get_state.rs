@@ -0,0 +1,82 @@
+use super::*;
+
+const REQUEST_TYPE: u8 = 0b00100001;
+const DFU_GETSTATE: u8 = 5;
+
+/// Command that queries the state of the device.
+///
+/// Unlike [`get_status::GetStatus`], this does not cause any state transition.
+#[must_use]
+pub struct GetState<'dfu, IO: DfuIo> {
+    pub(crate) dfu: &'dfu DfuSansIo<IO>,
+}
+
+impl<'dfu, IO: DfuIo> GetState<'dfu, IO> {
+    /// Query the state of the device.
+    pub fn get_state(self, buffer: &mut [u8]) -> Result<(GetStateRecv, IO::Read), IO::Error> {
+        debug_assert!(!buffer.is_empty());
+        let next = GetStateRecv;
+        let res = self
+            .dfu
+            .io
+            .read_control(REQUEST_TYPE, DFU_GETSTATE, 0, &mut buffer[..1])?;
+        Ok((next, res))
+    }
+}
+
+/// Read state after getting it from the device.
+#[must_use]
+pub struct GetStateRecv;
+
+impl GetStateRecv {
+    /// Returns the state of the device.
+    ///
+    /// `dfuERROR` is returned as any other state.
+    pub fn chain(self, bytes: &[u8]) -> Result<State, Error> {
+        match bytes.first() {
+            Some(state) => Ok(State::from(*state)),
+            None => Err(Error::ResponseTooShort {
+                got: 0,
+                expected: 1,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{Device, Request};
+
+    #[test]
+    fn get_state() {
+        // the device waiting for DFU_GETSTATUS after a block stays in dfuDNLOAD-SYNC
+        let device = Device::new(Protocol::DfuSe);
+        device.state.set(State::DfuUnloadSync);
+        let dfu = DfuSansIo::new(&device, mock::ADDRESS);
+        let mut buffer = [0; 1];
+        let (cmd, n) = dfu.get_state().get_state(&mut buffer).unwrap();
+        assert_eq!(cmd.chain(&buffer[..n]).unwrap(), State::DfuUnloadSync);
+        assert_eq!(device.state.get(), State::DfuUnloadSync);
+
+        let mut dfu = sync::DfuSync::new(&device, mock::ADDRESS);
+        assert_eq!(dfu.state().unwrap(), State::DfuUnloadSync);
+        assert_eq!(device.state.get(), State::DfuUnloadSync);
+        assert_eq!(
+            device.take_requests(),
+            vec![Request::GetState, Request::GetState]
+        );
+
+        // dfuERROR is not an error
+        device.state.set(State::DfuError);
+        assert_eq!(dfu.state().unwrap(), State::DfuError);
+
+        assert!(matches!(
+            GetStateRecv.chain(&[]),
+            Err(Error::ResponseTooShort {
+                got: 0,
+                expected: 1
+            })
+        ));
+    }
+}
@@ -48,6 +48,9 @@ pub struct GetStatusRecv<T: ChainedCommand<Arg = GetStatusMessage>> {
 // TODO: this impl does not use ChainedCommand because the argument has an anonymous lifetime.
 impl<T: ChainedCommand<Arg = GetStatusMessage>> GetStatusRecv<T> {
     /// Chain this command into another.
+    ///
+    /// When the status is in error, the index of its description (`iString`) is returned in
+    /// [`Error::StatusError`] to be resolved by the IO layer (see [`Error::describe`]).
     pub fn chain(self, mut bytes: &[u8]) -> Result<T::Into, Error> {
         if bytes.len() < 6 {
             return Err(Error::ResponseTooShort {
@@ -76,31 +79,23 @@ impl<T: ChainedCommand<Arg = GetStatusMessage>> GetStatusRecv<T> {
             other => Status::Other(other),
         };
         let poll_timeout = bytes.get_uint_le(3);
-        let state = match bytes.get_u8() {
-            0 => State::AppIdle,
-            1 => State::AppDetach,
-            2 => State::DfuIdle,
-            3 => State::DfuUnloadSync,
-            4 => State::DfuDnbusy,
-            5 => State::DfuDnloadIdle,
-            6 => State::DfuManifestSync,
-            7 => State::DfuManifest,
-            8 => State::DfuManifestWaitReset,
-            9 => State::DfuUploadIdle,
-            10 => State::DfuError,
-            other => State::Other(other),
-        };
+        let state = State::from(bytes.get_u8());
         let i_string = bytes.get_u8();
-
-        status.raise_error()?;
-        state.raise_error()?;
-
-        Ok(self.chained_command.chain(GetStatusMessage {
+        let message = GetStatusMessage {
             status,
             poll_timeout,
             state,
             index: i_string,
-        }))
+        };
+
+        if let Err(err) = status
+            .raise_error(i_string)
+            .and_then(|_| state.raise_error())
+        {
+            return self.chained_command.chain_error(message, err);
+        }
+
+        Ok(self.chained_command.chain(message))
     }
 }
 
@@ -133,6 +128,8 @@ pub struct WaitState<'dfu, IO: DfuIo, T> {
     end: bool,
     poll_timeout: u64,
     in_manifest: bool,
+    retry: Option<T>,
+    recover: bool,
 }
 
 /// A step when waiting for a state.
@@ -141,8 +138,10 @@ pub enum Step<'dfu, IO: DfuIo, T> {
     Break(T),
     /// The state has not been reached and the status of the device must be queried.
     Wait(GetStatus<'dfu, IO, WaitState<'dfu, IO, T>>, u64),
-    /// The device is in manifest state and might require a USB reset.
-    ManifestWaitReset(Option<reset::UsbReset<'dfu, IO, ()>>),
+    /// The device reported an error: its status must be cleared before retrying.
+    Recover(ClearStatus<'dfu, IO, T>),
+    /// The device is in manifest state and is going to leave the bus.
+    ManifestWaitReset(reset::Step<'dfu, IO, ()>),
 }
 
 impl<'dfu, IO: DfuIo, T> WaitState<'dfu, IO, T> {
@@ -155,20 +154,29 @@ impl<'dfu, IO: DfuIo, T> WaitState<'dfu, IO, T> {
             end: false,
             poll_timeout: 0,
             in_manifest: false,
+            retry: None,
+            recover: false,
         }
     }
 
+    /// Recover from an error reported by the device with this command instead of failing.
+    pub(crate) fn with_retry(self, retry: Option<T>) -> Self {
+        Self { retry, ..self }
+    }
+
     /// Returns the next command after waiting for a state.
     pub fn next(self) -> Step<'dfu, IO, T> {
         let func_desc = self.dfu.io.functional_descriptor();
 
-        if self.end {
+        if self.recover {
+            Step::Recover(ClearStatus {
+                dfu: self.dfu,
+                chained_command: self.chained_command,
+            })
+        } else if self.end {
             Step::Break(self.chained_command)
         } else if self.in_manifest && !func_desc.manifestation_tolerant {
-            Step::ManifestWaitReset((!func_desc.will_detach).then(|| reset::UsbReset {
-                dfu: self.dfu,
-                chained_command: (),
-            }))
+            Step::ManifestWaitReset(reset::Step::new(self.dfu, self.poll_timeout, ()))
         } else {
             let poll_timeout = self.poll_timeout;
 
@@ -203,6 +211,24 @@ impl<'dfu, IO: DfuIo, T> ChainedCommand for WaitState<'dfu, IO, T> {
             end: state == self.state,
             poll_timeout,
             in_manifest: state == State::DfuManifest,
+            retry: self.retry,
+            recover: false,
+        }
+    }
+
+    fn chain_error(self, _: Self::Arg, error: Error) -> Result<Self::Into, Error> {
+        match self.retry {
+            Some(retry) => Ok(WaitState {
+                dfu: self.dfu,
+                chained_command: retry,
+                state: self.state,
+                end: false,
+                poll_timeout: 0,
+                in_manifest: false,
+                retry: None,
+                recover: true,
+            }),
+            None => Err(error),
         }
     }
 }
@@ -0,0 +1,205 @@
+use displaydoc::Display;
+#[cfg(any(feature = "std", test))]
+use std::prelude::v1::*;
+#[cfg(any(feature = "std", test))]
+use thiserror::Error;
+
+#[cfg(any(feature = "std", test))]
+const RECORD_DATA: u8 = 0x00;
+#[cfg(any(feature = "std", test))]
+const RECORD_END_OF_FILE: u8 = 0x01;
+#[cfg(any(feature = "std", test))]
+const RECORD_EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+#[cfg(any(feature = "std", test))]
+const RECORD_START_SEGMENT_ADDRESS: u8 = 0x03;
+#[cfg(any(feature = "std", test))]
+const RECORD_EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+#[cfg(any(feature = "std", test))]
+const RECORD_START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Error while parsing an Intel HEX file.
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(any(feature = "std", test), derive(Error))]
+pub enum Error {
+    /// invalid record at line {0}
+    InvalidRecord(usize),
+    /// invalid checksum at line {0}
+    InvalidChecksum(usize),
+    /// unsupported record type {1:#04x} at line {0}
+    UnsupportedRecordType(usize, u8),
+    /// address overflow at line {0}
+    AddressOverflow(usize),
+    /// overlapping data at {0:#010x}
+    OverlappingData(u32),
+    /// missing end of file record
+    MissingEndOfFile,
+}
+
+/// The data of an Intel HEX file: its contiguous segments at their addresses.
+#[cfg(any(feature = "std", test))]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[derive(Debug, Clone, Default)]
+pub struct IntelHex {
+    segments: Vec<(u32, Vec<u8>)>,
+}
+
+#[cfg(any(feature = "std", test))]
+impl IntelHex {
+    /// Returns the contiguous segments sorted by address.
+    pub fn segments(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        self.segments
+            .iter()
+            .map(|(address, data)| (*address, data.as_slice()))
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+fn parse_record(line: &str, line_number: usize) -> Result<Vec<u8>, Error> {
+    let hex = line
+        .strip_prefix(':')
+        .filter(|hex| hex.len() % 2 == 0 && hex.is_ascii())
+        .ok_or(Error::InvalidRecord(line_number))?;
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::InvalidRecord(line_number))?;
+
+    if bytes.len() < 5 || bytes.len() != 5 + bytes[0] as usize {
+        return Err(Error::InvalidRecord(line_number));
+    }
+    if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err(Error::InvalidChecksum(line_number));
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(any(feature = "std", test))]
+impl core::convert::TryFrom<&str> for IntelHex {
+    type Error = Error;
+
+    fn try_from(src: &str) -> Result<Self, Self::Error> {
+        let mut segments: Vec<(u32, Vec<u8>)> = Vec::new();
+        let mut base = 0u32;
+        let mut end_of_file = false;
+
+        for (i, line) in src.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let bytes = parse_record(line, line_number)?;
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]);
+            let data = &bytes[4..bytes.len() - 1];
+            let value = || {
+                use core::convert::TryInto;
+
+                data.try_into()
+                    .map(u16::from_be_bytes)
+                    .map_err(|_| Error::InvalidRecord(line_number))
+            };
+
+            match bytes[3] {
+                RECORD_DATA => {
+                    let address = base
+                        .checked_add(offset as u32)
+                        .filter(|address| address.checked_add(data.len() as u32).is_some())
+                        .ok_or(Error::AddressOverflow(line_number))?;
+
+                    match segments.last_mut() {
+                        Some((start, segment))
+                            if *start as usize + segment.len() == address as usize =>
+                        {
+                            segment.extend_from_slice(data);
+                        }
+                        _ => segments.push((address, data.to_vec())),
+                    }
+                }
+                RECORD_END_OF_FILE => {
+                    end_of_file = true;
+                    break;
+                }
+                RECORD_EXTENDED_SEGMENT_ADDRESS => base = (value()? as u32) << 4,
+                RECORD_EXTENDED_LINEAR_ADDRESS => base = (value()? as u32) << 16,
+                // the entry point is not needed to download the firmware
+                RECORD_START_SEGMENT_ADDRESS | RECORD_START_LINEAR_ADDRESS => {}
+                other => return Err(Error::UnsupportedRecordType(line_number, other)),
+            }
+        }
+
+        if !end_of_file {
+            return Err(Error::MissingEndOfFile);
+        }
+
+        // records are not necessarily sorted by address
+        segments.sort_by_key(|(address, _)| *address);
+        let mut merged: Vec<(u32, Vec<u8>)> = Vec::with_capacity(segments.len());
+        for (address, data) in segments {
+            match merged.last_mut() {
+                Some((start, segment)) if (*start as usize + segment.len()) > address as usize => {
+                    return Err(Error::OverlappingData(address));
+                }
+                Some((start, segment)) if *start as usize + segment.len() == address as usize => {
+                    segment.extend_from_slice(&data);
+                }
+                _ => merged.push((address, data)),
+            }
+        }
+
+        Ok(Self { segments: merged })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::TryFrom;
+
+    #[test]
+    fn parsing() {
+        let src = "\
+            :020000040800F2\n\
+            :0100100007E8\n\
+            :0400000001020304F2\n\
+            :020004000506EF\n\
+            :020000021000EC\n\
+            :0100000008F7\n\
+            :040000050800018965\n\
+            :00000001FF\n";
+        let ihex = IntelHex::try_from(src).unwrap();
+
+        assert_eq!(
+            ihex.segments().collect::<Vec<_>>(),
+            vec![
+                (0x00010000, &[8][..]),
+                (0x08000000, &[1, 2, 3, 4, 5, 6][..]),
+                (0x08000010, &[7][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn parsing_errors() {
+        assert!(matches!(
+            IntelHex::try_from(":0400000001020304F3\n:00000001FF"),
+            Err(Error::InvalidChecksum(1))
+        ));
+        // nothing is read after the end of file record
+        assert!(IntelHex::try_from(":00000001FF\n0400000001020304F2").is_ok());
+        assert!(matches!(
+            IntelHex::try_from(":0400000001020304F2\n0400000001020304F2\n:00000001FF"),
+            Err(Error::InvalidRecord(2))
+        ));
+        assert!(matches!(
+            IntelHex::try_from(":0400000001020304F2"),
+            Err(Error::MissingEndOfFile)
+        ));
+        assert!(matches!(
+            IntelHex::try_from(":0400000001020304F2\n:0100010007F7\n:00000001FF"),
+            Err(Error::OverlappingData(0x00000001))
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+use super::*;
+
+const REQUEST_TYPE: u8 = 0b00100001;
+
+/// Starting point to leave the DFU mode of a DfuSe device.
+#[must_use]
+pub struct Start<'dfu, IO: DfuIo> {
+    pub(crate) dfu: &'dfu DfuSansIo<IO>,
+    pub(crate) address: u32,
+}
+
+impl<'dfu, IO: DfuIo> ChainedCommand for Start<'dfu, IO> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<SetAddress<'dfu, IO>, Error>;
+
+    fn chain(
+        self,
+        get_status::GetStatusMessage {
+            status: _,
+            poll_timeout: _,
+            state,
+            index: _,
+        }: Self::Arg,
+    ) -> Self::Into {
+        if matches!(state, State::DfuIdle | State::DfuDnloadIdle) {
+            Ok(SetAddress {
+                dfu: self.dfu,
+                address: self.address,
+            })
+        } else {
+            Err(Error::InvalidState {
+                got: state,
+                expected: State::DfuIdle,
+            })
+        }
+    }
+}
+
+/// Set the address of the entry point of the application.
+#[must_use]
+pub struct SetAddress<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+    address: u32,
+}
+
+impl<'dfu, IO: DfuIo> SetAddress<'dfu, IO> {
+    /// Set the address of the entry point of the application.
+    pub fn set_address(
+        self,
+    ) -> Result<(get_status::WaitState<'dfu, IO, Leave<'dfu, IO>>, IO::Write), IO::Error> {
+        let next =
+            get_status::WaitState::new(self.dfu, State::DfuDnloadIdle, Leave { dfu: self.dfu });
+        let res = self.dfu.io.write_control(
+            REQUEST_TYPE,
+            download::DFU_DNLOAD,
+            0,
+            &<[u8; 5]>::from(download::DownloadCommandSetAddress(self.address)),
+        )?;
+
+        Ok((next, res))
+    }
+}
+
+/// Leave the DFU mode and jump to the application.
+#[must_use]
+pub struct Leave<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+}
+
+impl<'dfu, IO: DfuIo> Leave<'dfu, IO> {
+    /// Send a zero-length download to the device.
+    ///
+    /// The device leaves the DFU mode when the status is queried.
+    pub fn leave(
+        self,
+    ) -> Result<
+        (
+            get_status::GetStatus<'dfu, IO, Leaving<'dfu, IO>>,
+            IO::Write,
+        ),
+        IO::Error,
+    > {
+        let next = get_status::GetStatus {
+            dfu: self.dfu,
+            chained_command: Leaving { dfu: self.dfu },
+        };
+        let res = self
+            .dfu
+            .io
+            .write_control(REQUEST_TYPE, download::DFU_DNLOAD, 2, &[])?;
+
+        Ok((next, res))
+    }
+}
+
+/// The device is leaving the DFU mode.
+#[must_use]
+pub struct Leaving<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+}
+
+impl<'dfu, IO: DfuIo> ChainedCommand for Leaving<'dfu, IO> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = reset::Reconnect<'dfu, IO, ()>;
+
+    fn chain(
+        self,
+        get_status::GetStatusMessage {
+            status: _,
+            poll_timeout,
+            state: _,
+            index: _,
+        }: Self::Arg,
+    ) -> Self::Into {
+        reset::Reconnect {
+            dfu: self.dfu,
+            timeout: poll_timeout,
+            chained_command: (),
+        }
+    }
+}
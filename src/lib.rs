@@ -8,22 +8,44 @@
 #[macro_use]
 extern crate std;
 
+/// Commands to abort the current operation.
+pub mod abort;
 /// Commands to detach the device.
 pub mod detach;
+/// DfuSe file format.
+pub mod dfuse_file;
 /// Commands to download a firmware into the device.
 pub mod download;
 /// Functional descriptor.
 pub mod functional_descriptor;
+/// Commands to query the DfuSe commands supported by the device.
+pub mod get_commands;
+/// Commands to get the state of the device.
+pub mod get_state;
 /// Commands to get the status of the device.
 pub mod get_status;
+/// Intel HEX file format.
+pub mod ihex;
+/// Commands to leave the DFU mode of the device.
+pub mod leave;
+/// Commands to erase the whole memory of the device.
+pub mod mass_erase;
 /// Memory layout.
 pub mod memory_layout;
+#[cfg(test)]
+mod mock;
+/// Commands to remove the read protection of the device.
+pub mod read_unprotect;
 /// Commands to reset the device.
 pub mod reset;
+/// DFU suffix of firmware files.
+pub mod suffix;
 /// Generic synchronous implementation.
 #[cfg(any(feature = "std", test))]
 #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
 pub mod sync;
+/// Commands to upload a firmware from the device.
+pub mod upload;
 
 use displaydoc::Display;
 #[cfg(any(feature = "std", test))]
@@ -53,10 +75,84 @@ pub enum Error {
     UnrecognizedStateCode(u8),
     /// Device response is too short (got: {got:?}, expected: {expected:?}).
     ResponseTooShort { got: usize, expected: usize },
-    /// Device status is in error: {0}
-    StatusError(Status),
+    /// Device status is in error: {status} {description}
+    StatusError {
+        status: Status,
+        index: u8,
+        description: StatusDescription,
+    },
     /// Device state is in error: {0}
     StateError(State),
+    /// Address {0:#010x} is outside of the memory layout.
+    AddressOutOfRange(u32),
+    /// Page at {0:#010x} is not readable.
+    NotReadable(u32),
+    /// Page at {0:#010x} is not erasable.
+    NotErasable(u32),
+    /// Page at {0:#010x} is not writable.
+    NotWritable(u32),
+    /// Segments must not be empty, must be sorted by address and must not overlap.
+    InvalidSegments,
+    /// Plain DFU cannot download to different addresses.
+    AddressingNotSupported,
+    /// The command requires the DfuSe extensions.
+    DfuseRequired,
+    /// The device does not support the command {0:#04x}.
+    UnsupportedCommand(u8),
+    /// The data ended before the end of the firmware (got: {got:#010x}, expected: {expected:#010x}).
+    UnexpectedEndOfData { got: u32, expected: u32 },
+    /// The device re-enumerated and must be opened again.
+    ReconnectRequired,
+    /// The device cannot read back the firmware to verify it.
+    VerifyNotSupported,
+    /// Verification failed: {count} bytes differ starting at {address:#010x}.
+    VerifyMismatch { address: u32, count: u32 },
+    /// Invalid DfuSe file: {0}
+    DfuseFile(dfuse_file::Error),
+    /// Invalid DFU suffix: {0}
+    Suffix(suffix::Error),
+    /// Invalid Intel HEX file: {0}
+    IntelHex(ihex::Error),
+    /// The firmware targets another device ({vendor_id:04x}:{product_id:04x}).
+    DeviceMismatch { vendor_id: u16, product_id: u16 },
+}
+
+impl Error {
+    /// Describe a [`Error::StatusError`] with the string descriptor of its index (`iString`).
+    ///
+    /// The description is left empty if the device has none (see [`DfuIo::string_descriptor`]).
+    pub fn describe<IO: DfuIo + ?Sized>(self, io: &IO) -> Self {
+        match self {
+            Error::StatusError {
+                status,
+                index,
+                description,
+            } if index != 0 && description.is_empty() => Error::StatusError {
+                status,
+                index,
+                description: io.string_descriptor(index).unwrap_or_default(),
+            },
+            err => err,
+        }
+    }
+}
+
+impl From<suffix::Error> for Error {
+    fn from(err: suffix::Error) -> Self {
+        Error::Suffix(err)
+    }
+}
+
+impl From<dfuse_file::Error> for Error {
+    fn from(err: dfuse_file::Error) -> Self {
+        Error::DfuseFile(err)
+    }
+}
+
+impl From<ihex::Error> for Error {
+    fn from(err: ihex::Error) -> Self {
+        Error::IntelHex(err)
+    }
 }
 
 /// Trait to implement lower level communication with a USB device.
@@ -91,47 +187,412 @@ pub trait DfuIo {
     /// Triggers a USB reset.
     fn usb_reset(&self) -> Result<Self::Reset, Self::Error>;
 
-    /// Returns the memory layout of the device.
-    fn memory_layout(&self) -> &memory_layout::mem;
+    /// Wait for the device to re-enumerate and open it again.
+    ///
+    /// This is needed when the device leaves the bus: after a detach, a read unprotect, a leave or
+    /// the manifestation of a device that is not manifestation tolerant. The device announced to
+    /// take `timeout` milliseconds before leaving the bus.
+    ///
+    /// Returns the device opened again or `None` if it cannot be, which is the default. The
+    /// operations that need the device afterwards, like a download that started with a detach
+    /// (see [`download::Step::Detach`]), then fail with [`Error::ReconnectRequired`].
+    fn reconnect(&self, timeout: u64) -> Option<Result<Self, Self::Error>>
+    where
+        Self: Sized,
+    {
+        let _ = timeout;
+        None
+    }
+
+    /// Returns the memory layout of the device: its pages with their addresses.
+    fn memory_layout(&self) -> &[memory_layout::Page];
 
     /// Returns the functional descriptor of the device.
     fn functional_descriptor(&self) -> &functional_descriptor::FunctionalDescriptor;
+
+    /// Returns the vendor ID and the product ID of the device.
+    ///
+    /// They are used to check that a firmware file targets the device. The default implementation
+    /// returns `None`.
+    fn device_ids(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    /// Returns the string descriptor at `index`.
+    ///
+    /// It describes the errors reported by the device with `iString` in its status. The default
+    /// implementation returns `None`.
+    fn string_descriptor(&self, index: u8) -> Option<StatusDescription> {
+        let _ = index;
+        None
+    }
+}
+
+/// DFU protocol spoken by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Plain DFU 1.1: every download payload is firmware data.
+    Dfu,
+    /// STM32 DfuSe extensions: download payloads of block 0 are commands (erase, set address, ...).
+    DfuSe,
+}
+
+/// What to do when the device reports an error during a download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryPolicy {
+    /// Stop the download and return the error.
+    #[default]
+    Abort,
+    /// Clear the status and retry the failed erase, set address or write up to this number of
+    /// times.
+    ///
+    /// Only DfuSe devices can retry: a plain DFU device cannot resume a download.
+    Retry(u8),
+}
+
+impl RecoveryPolicy {
+    /// Returns the number of retries allowed for a single operation.
+    pub fn retries(&self) -> u8 {
+        match self {
+            RecoveryPolicy::Abort => 0,
+            RecoveryPolicy::Retry(retries) => *retries,
+        }
+    }
 }
 
 /// Use this struct to create state machines to make operations on the device.
 pub struct DfuSansIo<IO> {
     io: IO,
     address: u32,
+    protocol: Protocol,
+    commands: Option<get_commands::Commands>,
+    recovery_policy: RecoveryPolicy,
 }
 
 impl<IO: DfuIo> DfuSansIo<IO> {
     /// Create an instance of [`DfuSansIo`].
+    ///
+    /// The protocol is inferred from the functional descriptor of the device.
     pub fn new(io: IO, address: u32) -> Self {
-        Self { io, address }
+        let protocol = io.functional_descriptor().protocol();
+
+        Self {
+            io,
+            address,
+            protocol,
+            commands: None,
+            recovery_policy: RecoveryPolicy::default(),
+        }
+    }
+
+    /// Override the protocol inferred from the functional descriptor.
+    pub fn with_protocol(self, protocol: Protocol) -> Self {
+        Self { protocol, ..self }
+    }
+
+    /// Returns the protocol used to talk to the device.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Use the DfuSe commands supported by the device to fail early when a command is missing.
+    ///
+    /// See [`Self::get_commands`] to query the commands from the device.
+    pub fn with_commands(self, commands: get_commands::Commands) -> Self {
+        Self {
+            commands: Some(commands),
+            ..self
+        }
+    }
+
+    /// Returns the DfuSe commands supported by the device, if known.
+    pub fn commands(&self) -> Option<get_commands::Commands> {
+        self.commands
+    }
+
+    /// Set what to do when the device reports an error during a download.
+    pub fn with_recovery_policy(self, recovery_policy: RecoveryPolicy) -> Self {
+        Self {
+            recovery_policy,
+            ..self
+        }
+    }
+
+    /// Returns what is done when the device reports an error during a download.
+    pub fn recovery_policy(&self) -> RecoveryPolicy {
+        self.recovery_policy
+    }
+
+    fn check_command(&self, command: u8) -> Result<(), Error> {
+        match self.commands {
+            Some(commands) if !commands.contains(command) => {
+                Err(Error::UnsupportedCommand(command))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Create a command to query the state of the device.
+    pub fn get_state(&self) -> get_state::GetState<'_, IO> {
+        get_state::GetState { dfu: self }
+    }
+
+    /// Create a state machine to switch a device running its application to DFU mode.
+    pub fn detach(&self) -> detach::Detach<'_, IO, detach::Attach<'_, IO, ()>> {
+        detach::Detach {
+            dfu: self,
+            chained_command: detach::Attach {
+                dfu: self,
+                chained_command: (),
+            },
+        }
     }
 
     /// Create a state machine to download the firmware into the device.
+    ///
+    /// A device running its application is detached first (see [`download::Step::Detach`]) and a
+    /// transfer left unfinished by a previous session is aborted (see [`download::Step::Abort`]).
+    ///
+    /// Once the device is in DFU mode and before anything is written, the pages of the memory
+    /// layout receiving the firmware are checked to be writable with DfuSe. The pages that cannot
+    /// be erased are written without being erased.
     pub fn download(
         &self,
         length: u32,
+    ) -> Result<get_status::GetStatus<'_, IO, download::Start<'_, IO>>, Error> {
+        let end_pos = self.address.checked_add(length).ok_or(Error::NoSpaceLeft)?;
+
+        Ok(get_status::GetStatus {
+            dfu: self,
+            chained_command: download::Start {
+                dfu: self,
+                memory_layout: self.io.memory_layout(),
+                address: self.address,
+                end_pos,
+                segments: &[],
+                recovered: false,
+            },
+        })
+    }
+
+    /// Create a state machine to download multiple segments of a firmware into a DfuSe device.
+    ///
+    /// Each segment is written at its own address and only the pages overlapping the segments are
+    /// erased: the data between the segments is preserved. The segments must be sorted by address
+    /// and must not overlap. Plain DFU can only download a single segment.
+    pub fn download_segments<'a>(
+        &'a self,
+        segments: &'a [download::Segment],
+    ) -> Result<get_status::GetStatus<'a, IO, download::Start<'a, IO>>, Error> {
+        let (first, rest) = segments.split_first().ok_or(Error::InvalidSegments)?;
+        let mut pos = first.address;
+
+        for segment in segments {
+            if segment.address < pos {
+                return Err(Error::InvalidSegments);
+            }
+            pos = segment.end()?;
+        }
+
+        Ok(get_status::GetStatus {
+            dfu: self,
+            chained_command: download::Start {
+                dfu: self,
+                memory_layout: self.io.memory_layout(),
+                address: first.address,
+                end_pos: first.end()?,
+                segments: rest,
+                recovered: false,
+            },
+        })
+    }
+
+    /// Create a state machine to upload the firmware from the device.
+    ///
+    /// With DfuSe, the memory is read from the address given to [`DfuSansIo::new`] (see
+    /// [`Self::upload_dfuse`]) as the block 0 holds the commands supported by the device.
+    pub fn upload(
+        &self,
+        length: u32,
     ) -> Result<
-        get_status::ClearStatus<'_, IO, get_status::GetStatus<'_, IO, download::Start<'_, IO>>>,
+        get_status::ClearStatus<'_, IO, get_status::GetStatus<'_, IO, upload::Start<'_, IO>>>,
         Error,
     > {
+        if self.protocol == Protocol::DfuSe {
+            return self.upload_dfuse(self.address, length);
+        }
+
+        Ok(get_status::ClearStatus {
+            dfu: self,
+            chained_command: get_status::GetStatus {
+                dfu: self,
+                chained_command: upload::Start {
+                    dfu: self,
+                    address: None,
+                    length,
+                    aborted: false,
+                },
+            },
+        })
+    }
+
+    /// Create a state machine to upload a window of the memory of a DfuSe device.
+    ///
+    /// The address pointer is set to `address` before reading and the window
+    /// `[address, address + length)` must be within the readable pages of the memory layout of the
+    /// device.
+    pub fn upload_dfuse(
+        &self,
+        address: u32,
+        length: u32,
+    ) -> Result<
+        get_status::ClearStatus<'_, IO, get_status::GetStatus<'_, IO, upload::Start<'_, IO>>>,
+        Error,
+    > {
+        if self.protocol != Protocol::DfuSe {
+            return Err(Error::DfuseRequired);
+        }
+        self.check_command(get_commands::COMMAND_SET_ADDRESS)?;
+
+        let end = address
+            .checked_add(length)
+            .ok_or(Error::AddressOutOfRange(address))?;
+
+        if let Some(address) = memory_layout::uncovered(self.io.memory_layout(), address, end) {
+            return Err(Error::AddressOutOfRange(address));
+        }
+        if let Some(page) = memory_layout::pages_overlapping(self.io.memory_layout(), address, end)
+            .find(|page| !page.permissions.readable)
+        {
+            return Err(Error::NotReadable(page.address));
+        }
+
         Ok(get_status::ClearStatus {
             dfu: self,
             chained_command: get_status::GetStatus {
                 dfu: self,
-                chained_command: download::Start {
+                chained_command: upload::Start {
                     dfu: self,
-                    memory_layout: self.io.memory_layout(),
-                    address: self.address,
-                    end_pos: self.address.checked_add(length).ok_or(Error::NoSpaceLeft)?,
+                    address: Some(address),
+                    length,
+                    aborted: false,
                 },
             },
         })
     }
 
+    /// Create a state machine to erase the whole memory of a DfuSe device.
+    pub fn mass_erase(
+        &self,
+    ) -> Result<
+        get_status::ClearStatus<'_, IO, get_status::GetStatus<'_, IO, mass_erase::Start<'_, IO>>>,
+        Error,
+    > {
+        if self.protocol != Protocol::DfuSe {
+            return Err(Error::DfuseRequired);
+        }
+        self.check_command(get_commands::COMMAND_ERASE)?;
+
+        Ok(get_status::ClearStatus {
+            dfu: self,
+            chained_command: get_status::GetStatus {
+                dfu: self,
+                chained_command: mass_erase::Start {
+                    dfu: self,
+                    aborted: false,
+                },
+            },
+        })
+    }
+
+    /// Create a state machine to query the commands supported by a DfuSe device.
+    pub fn get_commands(
+        &self,
+    ) -> Result<
+        get_status::ClearStatus<'_, IO, get_status::GetStatus<'_, IO, get_commands::Start<'_, IO>>>,
+        Error,
+    > {
+        if self.protocol != Protocol::DfuSe {
+            return Err(Error::DfuseRequired);
+        }
+
+        Ok(get_status::ClearStatus {
+            dfu: self,
+            chained_command: get_status::GetStatus {
+                dfu: self,
+                chained_command: get_commands::Start {
+                    dfu: self,
+                    aborted: false,
+                },
+            },
+        })
+    }
+
+    /// Create a state machine to remove the read protection of a DfuSe device.
+    ///
+    /// The device erases its whole memory and resets itself afterwards.
+    pub fn read_unprotect(
+        &self,
+    ) -> Result<
+        get_status::ClearStatus<
+            '_,
+            IO,
+            get_status::GetStatus<'_, IO, read_unprotect::Start<'_, IO>>,
+        >,
+        Error,
+    > {
+        if self.protocol != Protocol::DfuSe {
+            return Err(Error::DfuseRequired);
+        }
+        self.check_command(get_commands::COMMAND_READ_UNPROTECT)?;
+
+        Ok(get_status::ClearStatus {
+            dfu: self,
+            chained_command: get_status::GetStatus {
+                dfu: self,
+                chained_command: read_unprotect::Start {
+                    dfu: self,
+                    aborted: false,
+                },
+            },
+        })
+    }
+
+    /// Create a state machine to leave the DFU mode of a DfuSe device.
+    ///
+    /// The device jumps to the application whose entry point is at `address`.
+    pub fn leave(
+        &self,
+        address: u32,
+    ) -> Result<
+        get_status::ClearStatus<'_, IO, get_status::GetStatus<'_, IO, leave::Start<'_, IO>>>,
+        Error,
+    > {
+        if self.protocol != Protocol::DfuSe {
+            return Err(Error::DfuseRequired);
+        }
+        self.check_command(get_commands::COMMAND_SET_ADDRESS)?;
+
+        Ok(get_status::ClearStatus {
+            dfu: self,
+            chained_command: get_status::GetStatus {
+                dfu: self,
+                chained_command: leave::Start { dfu: self, address },
+            },
+        })
+    }
+
+    /// Replace the IO by the device opened again after it re-enumerated (see
+    /// [`DfuIo::reconnect`]) and return the previous one.
+    ///
+    /// The protocol is inferred again from the functional descriptor of the device and the DfuSe
+    /// commands are forgotten.
+    pub fn replace_io(&mut self, io: IO) -> IO {
+        self.protocol = io.functional_descriptor().protocol();
+        self.commands = None;
+        core::mem::replace(&mut self.io, io)
+    }
+
     /// Consume the object and return its [`DfuIo`] and address.
     pub fn into_parts(self) -> (IO, u32) {
         (self.io, self.address)
@@ -180,15 +641,77 @@ pub enum Status {
 }
 
 impl Status {
-    pub(crate) fn raise_error(&self) -> Result<(), Error> {
+    pub(crate) fn raise_error(&self, index: u8) -> Result<(), Error> {
         if !matches!(self, Status::Ok | Status::Other(_)) {
-            Err(Error::StatusError(*self))
+            Err(Error::StatusError {
+                status: *self,
+                index,
+                description: StatusDescription::default(),
+            })
         } else {
             Ok(())
         }
     }
 }
 
+/// Description of an error reported by the device (see [`DfuIo::string_descriptor`]).
+///
+/// The description is stored inline and truncated to [`StatusDescription::CAPACITY`] bytes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct StatusDescription {
+    bytes: [u8; StatusDescription::CAPACITY],
+    len: u8,
+}
+
+impl StatusDescription {
+    /// Maximum length of the description in bytes.
+    pub const CAPACITY: usize = 64;
+
+    /// Create a description from a string.
+    pub fn new(description: &str) -> Self {
+        let mut len = description.len().min(Self::CAPACITY);
+        while !description.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        let mut bytes = [0; Self::CAPACITY];
+        bytes[..len].copy_from_slice(&description.as_bytes()[..len]);
+
+        Self {
+            bytes,
+            len: len as u8,
+        }
+    }
+
+    /// Returns the description as a string.
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+
+    /// Returns `true` if the description is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for StatusDescription {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl core::fmt::Debug for StatusDescription {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl core::fmt::Display for StatusDescription {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// DFU State.
 ///
 /// Note: not the same as status!
@@ -230,6 +753,25 @@ impl State {
     }
 }
 
+impl From<u8> for State {
+    fn from(state: u8) -> Self {
+        match state {
+            0 => State::AppIdle,
+            1 => State::AppDetach,
+            2 => State::DfuIdle,
+            3 => State::DfuUnloadSync,
+            4 => State::DfuDnbusy,
+            5 => State::DfuDnloadIdle,
+            6 => State::DfuManifestSync,
+            7 => State::DfuManifest,
+            8 => State::DfuManifestWaitReset,
+            9 => State::DfuUploadIdle,
+            10 => State::DfuError,
+            other => State::Other(other),
+        }
+    }
+}
+
 /// A trait for commands that be chained into another.
 pub trait ChainedCommand {
     /// Type of the argument to pass with the command for chaining.
@@ -239,13 +781,40 @@ pub trait ChainedCommand {
 
     /// Chain this command into another.
     fn chain(self, arg: Self::Arg) -> Self::Into;
+
+    /// Chain this command into another when the device reported an error.
+    ///
+    /// The default implementation returns the error.
+    fn chain_error(self, arg: Self::Arg, error: Error) -> Result<Self::Into, Error>
+    where
+        Self: Sized,
+    {
+        let _ = arg;
+        Err(error)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::prelude::v1::*;
 
     // ensure DfuIo can be made into an object
     const _: [&dyn DfuIo<Read = (), Write = (), Reset = (), Error = Error>; 0] = [];
+
+    #[test]
+    fn status_description() {
+        assert_eq!(
+            StatusDescription::new("Flash locked").as_str(),
+            "Flash locked"
+        );
+        assert!(StatusDescription::default().is_empty());
+
+        // truncated on a char boundary
+        let long = "é".repeat(StatusDescription::CAPACITY);
+        let description = StatusDescription::new(&long);
+        assert_eq!(description.as_str().len(), StatusDescription::CAPACITY);
+        let long = format!("a{}", long);
+        let description = StatusDescription::new(&long);
+        assert_eq!(description.as_str().len(), StatusDescription::CAPACITY - 1);
+    }
 }
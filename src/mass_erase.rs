@@ -0,0 +1,81 @@
+use super::*;
+
+const REQUEST_TYPE: u8 = 0b00100001;
+
+/// Starting point to mass erase a DfuSe device.
+#[must_use]
+pub struct Start<'dfu, IO: DfuIo> {
+    pub(crate) dfu: &'dfu DfuSansIo<IO>,
+    pub(crate) aborted: bool,
+}
+
+impl<'dfu, IO: DfuIo> ChainedCommand for Start<'dfu, IO> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<abort::Step<'dfu, IO, Self, MassErase<'dfu, IO>>, Error>;
+
+    fn chain(
+        self,
+        get_status::GetStatusMessage {
+            status: _,
+            poll_timeout: _,
+            state,
+            index: _,
+        }: Self::Arg,
+    ) -> Self::Into {
+        let dfu = self.dfu;
+
+        abort::Step::new(
+            dfu,
+            state,
+            self.aborted,
+            || MassErase { dfu },
+            || Start { dfu, aborted: true },
+        )
+    }
+}
+
+/// Erase the whole memory of the device.
+#[must_use]
+pub struct MassErase<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+}
+
+impl<'dfu, IO: DfuIo> MassErase<'dfu, IO> {
+    /// Erase the whole memory of the device.
+    ///
+    /// The operation can take a long time: the device must be polled until it is back in
+    /// `dfuDNLOAD-IDLE`.
+    pub fn mass_erase(self) -> Result<(get_status::WaitState<'dfu, IO, ()>, IO::Write), IO::Error> {
+        let next = get_status::WaitState::new(self.dfu, State::DfuDnloadIdle, ());
+        let res = self.dfu.io.write_control(
+            REQUEST_TYPE,
+            download::DFU_DNLOAD,
+            0,
+            &<[u8; 1]>::from(download::DownloadCommandMassErase),
+        )?;
+
+        Ok((next, res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{Device, Request};
+
+    #[test]
+    fn mass_erase() {
+        let device = Device::new(Protocol::DfuSe);
+        device.busy.set(3);
+        let mut dfu = sync::DfuSync::new(&device, mock::ADDRESS);
+
+        // the device is polled until the erase is done
+        dfu.mass_erase().unwrap();
+        assert_eq!(
+            device.take_requests(),
+            vec![Request::ClrStatus, Request::MassErase]
+        );
+        assert_eq!(device.busy.get(), 0);
+        assert_eq!(device.state.get(), State::DfuDnloadIdle);
+    }
+}
@@ -18,6 +18,14 @@ pub enum Error {
     ParseErrorPageSize(String),
     /// invalid prefix: {0}
     InvalidPrefix(String),
+    /// address overflow: {0:#010x}
+    AddressOverflow(u32),
+    /// invalid interface string: {0}
+    InvalidInterfaceString(String),
+    /// could not parse address: {0}
+    ParseErrorAddress(String),
+    /// invalid permissions: {0}
+    InvalidPermissions(String),
 }
 
 /// A memory page size.
@@ -27,6 +35,90 @@ pub type MemoryPage = u32;
 #[allow(non_camel_case_types)]
 pub type mem = [MemoryPage];
 
+/// Permissions of a memory page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    /// The page can be read (uploaded).
+    pub readable: bool,
+    /// The page can be erased.
+    pub erasable: bool,
+    /// The page can be written (downloaded).
+    pub writable: bool,
+}
+
+impl Permissions {
+    /// Every operation is permitted.
+    pub const ALL: Self = Self {
+        readable: true,
+        erasable: true,
+        writable: true,
+    };
+
+    /// Read the permissions from a DfuSe sector type letter (`a` to `g`).
+    pub fn from_dfuse(letter: char) -> Option<Self> {
+        let bits = match letter {
+            'a'..='g' => letter as u8 - b'a' + 1,
+            _ => return None,
+        };
+
+        Some(Self {
+            readable: bits & (1 << 0) > 0,
+            erasable: bits & (1 << 1) > 0,
+            writable: bits & (1 << 2) > 0,
+        })
+    }
+}
+
+/// A memory page located at an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    /// Start address of the page.
+    pub address: u32,
+    /// Size of the page.
+    pub size: MemoryPage,
+    /// Permissions of the page.
+    pub permissions: Permissions,
+}
+
+impl Page {
+    /// Returns `true` if the address is in the page.
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.address && address - self.address < self.size
+    }
+
+    /// Returns `true` if the page overlaps the range `[start, end)`.
+    pub fn overlaps(&self, start: u32, end: u32) -> bool {
+        (self.address as u64) < end as u64
+            && (start as u64) < self.address as u64 + self.size as u64
+    }
+}
+
+/// Find the page containing the address.
+pub fn page_at(pages: &[Page], address: u32) -> Option<&Page> {
+    pages.iter().find(|page| page.contains(address))
+}
+
+/// Iterate over the pages overlapping the range `[start, end)`.
+pub fn pages_overlapping(pages: &[Page], start: u32, end: u32) -> impl Iterator<Item = &Page> {
+    pages.iter().filter(move |page| page.overlaps(start, end))
+}
+
+/// Returns the first address of the range `[start, end)` that is not in any page.
+pub fn uncovered(pages: &[Page], start: u32, end: u32) -> Option<u32> {
+    let mut pos = start;
+
+    while pos < end {
+        let page = match page_at(pages, pos) {
+            Some(page) => page,
+            None => return Some(pos),
+        };
+        // a page reaching the end of the address space covers the rest of the range
+        pos = page.address.checked_add(page.size)?;
+    }
+
+    None
+}
+
 /// Memory layout.
 #[cfg(any(feature = "std", test))]
 #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
@@ -77,42 +169,197 @@ impl core::ops::DerefMut for MemoryLayout {
     }
 }
 
+/// Memory map: the pages of the memory with their addresses.
+#[cfg(any(feature = "std", test))]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMap(Vec<Page>);
+
+#[cfg(any(feature = "std", test))]
+impl MemoryMap {
+    /// Create a new empty instance of [`MemoryMap`].
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Create a [`MemoryMap`] from a memory layout starting at `address`.
+    pub fn from_layout(address: u32, layout: &mem) -> Result<Self, Error> {
+        let mut pages = Vec::with_capacity(layout.len());
+        let mut pos = Some(address);
+
+        for size in layout {
+            let address = pos.ok_or(Error::AddressOverflow(address))?;
+            pages.push(Page {
+                address,
+                size: *size,
+                permissions: Permissions::ALL,
+            });
+            pos = address.checked_add(*size);
+        }
+
+        Ok(Self(pages))
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+impl AsRef<[Page]> for MemoryMap {
+    fn as_ref(&self) -> &[Page] {
+        self.0.as_slice()
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+impl From<Vec<Page>> for MemoryMap {
+    fn from(vec: Vec<Page>) -> Self {
+        Self(vec)
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+impl core::ops::Deref for MemoryMap {
+    type Target = Vec<Page>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+impl core::ops::DerefMut for MemoryMap {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Parse a DfuSe page list (`04*016Kg,01*064Kg`) into page sizes with their sector type letter.
+#[cfg(any(feature = "std", test))]
+fn parse_page_list(src: &str) -> Result<Vec<(MemoryPage, char)>, Error> {
+    use core::str::FromStr;
+
+    let mut pages = Vec::new();
+
+    for s in src.split(',') {
+        let (count, size) = s
+            .split_once('*')
+            .ok_or_else(|| Error::InvalidPageFormat(s.into()))?;
+        let (size, prefix) = size.split_at(
+            size.len()
+                .checked_sub(2)
+                .ok_or_else(|| Error::ParseErrorPageSize(size.into()))?,
+        );
+
+        let count = u32::from_str(count).map_err(|_| Error::ParseErrorPageCount(count.into()))?;
+        let size = u32::from_str(size).map_err(|_| Error::ParseErrorPageSize(size.into()))?;
+        let mut chars = prefix.chars();
+        let prefix = match chars.next() {
+            Some('K') => 1024,
+            Some('M') => 1024 * 1024,
+            Some(' ') => 1,
+            _ => return Err(Error::InvalidPrefix(prefix.into())),
+        };
+        let letter = chars
+            .next()
+            .ok_or_else(|| Error::InvalidPermissions(s.into()))?;
+
+        let size = size * prefix;
+        for _ in 0..count {
+            pages.push((size, letter));
+        }
+    }
+
+    Ok(pages)
+}
+
+#[cfg(any(feature = "std", test))]
+impl core::convert::TryFrom<&str> for MemoryLayout {
+    type Error = Error;
+
+    fn try_from(src: &str) -> Result<Self, Self::Error> {
+        Ok(Self(
+            parse_page_list(src)?
+                .into_iter()
+                .map(|(size, _)| size)
+                .collect(),
+        ))
+    }
+}
+
+/// DfuSe memory description, parsed from the string descriptor of an alternate setting.
+///
+/// Example: `@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg`.
+#[cfg(any(feature = "std", test))]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[derive(Debug, Clone)]
+pub struct DfuseMemory {
+    /// Name of the memory region.
+    pub name: String,
+    /// Segments of the memory, each one with its pages located from the segment address.
+    pub segments: Vec<MemoryMap>,
+}
+
+#[cfg(any(feature = "std", test))]
+impl DfuseMemory {
+    /// Returns the pages of all the segments.
+    pub fn memory_map(&self) -> MemoryMap {
+        MemoryMap(
+            self.segments
+                .iter()
+                .flat_map(|segment| segment.iter().copied())
+                .collect(),
+        )
+    }
+}
+
 #[cfg(any(feature = "std", test))]
-impl<'a> core::convert::TryFrom<&str> for MemoryLayout {
+impl core::convert::TryFrom<&str> for DfuseMemory {
     type Error = Error;
 
     fn try_from(src: &str) -> Result<Self, Self::Error> {
-        use core::str::FromStr;
-
-        let mut pages = Vec::new();
-
-        for s in src.split(',') {
-            let (count, size) = s
-                .split_once('*')
-                .ok_or_else(|| Error::InvalidPageFormat(s.into()))?;
-            let (size, prefix) = size.split_at(
-                size.len()
-                    .checked_sub(2)
-                    .ok_or_else(|| Error::ParseErrorPageSize(size.into()))?,
-            );
-
-            let count =
-                u32::from_str(count).map_err(|_| Error::ParseErrorPageCount(count.into()))?;
-            let size = u32::from_str(size).map_err(|_| Error::ParseErrorPageSize(size.into()))?;
-            let prefix = match &prefix[..1] {
-                "K" => 1024,
-                "M" => 1024 * 1024,
-                " " => 1,
-                other => return Err(Error::InvalidPrefix(other.into())),
-            };
-
-            let size = size * prefix;
-            for _ in 0..count {
-                pages.push(size);
+        let mut parts = src
+            .strip_prefix('@')
+            .ok_or_else(|| Error::InvalidInterfaceString(src.into()))?
+            .split('/');
+        let name = parts.next().unwrap_or_default().trim().to_string();
+        let mut segments = Vec::new();
+
+        while let Some(address) = parts.next().map(str::trim) {
+            // tolerate a trailing slash
+            if address.is_empty() {
+                continue;
+            }
+
+            let page_list = parts
+                .next()
+                .map(str::trim)
+                .ok_or_else(|| Error::InvalidInterfaceString(src.into()))?;
+            let mut address = address
+                .strip_prefix("0x")
+                .or_else(|| address.strip_prefix("0X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| Error::ParseErrorAddress(address.into()))?;
+            let mut pages = Vec::new();
+
+            for (size, letter) in parse_page_list(page_list)? {
+                let permissions = Permissions::from_dfuse(letter)
+                    .ok_or_else(|| Error::InvalidPermissions(letter.into()))?;
+                pages.push(Page {
+                    address,
+                    size,
+                    permissions,
+                });
+                address = address
+                    .checked_add(size)
+                    .ok_or(Error::AddressOverflow(address))?;
             }
+
+            segments.push(MemoryMap(pages));
         }
 
-        Ok(Self(pages))
+        if segments.is_empty() {
+            return Err(Error::InvalidInterfaceString(src.into()));
+        }
+
+        Ok(Self { name, segments })
     }
 }
 
@@ -140,4 +387,93 @@ mod tests {
         let m = MemoryLayout::try_from(s).unwrap();
         assert_eq!(m.as_slice(), &[32768, 32768, 32768, 32768, 131072]);
     }
+
+    #[test]
+    fn memory_map() {
+        let m = MemoryLayout::try_from("04*016Kg,01*064Kg,07*128Kg").unwrap();
+        let m = MemoryMap::from_layout(0x08000000, &m).unwrap();
+        assert_eq!(
+            page_at(&m, 0x08008000),
+            Some(&Page {
+                address: 0x08008000,
+                size: 16384,
+                permissions: Permissions::ALL,
+            })
+        );
+        assert_eq!(
+            page_at(&m, 0x08012345),
+            Some(&Page {
+                address: 0x08010000,
+                size: 65536,
+                permissions: Permissions::ALL,
+            })
+        );
+        assert_eq!(page_at(&m, 0x08100000), None);
+        assert_eq!(uncovered(&m, 0x08008000, 0x08100000), None);
+        assert_eq!(uncovered(&m, 0x07ffffff, 0x08001000), Some(0x07ffffff));
+        assert_eq!(uncovered(&m, 0x080f0000, 0x08100001), Some(0x08100000));
+    }
+
+    #[test]
+    fn parsing_dfuse_memory() {
+        let s = "@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg";
+        let m = DfuseMemory::try_from(s).unwrap();
+        assert_eq!(m.name, "Internal Flash");
+        assert_eq!(m.segments.len(), 1);
+        let map = m.memory_map();
+        assert_eq!(map.len(), 12);
+        assert_eq!(map[4].address, 0x08010000);
+        assert_eq!(map[4].size, 65536);
+        assert_eq!(map[11].address, 0x080e0000);
+        assert_eq!(map[11].permissions, Permissions::ALL);
+    }
+
+    #[test]
+    fn parsing_dfuse_memory_multiple_segments() {
+        let s = "@Option Bytes  /0x1FFFC000/01*016 e/0x1FFEC000/01*016 a";
+        let m = DfuseMemory::try_from(s).unwrap();
+        assert_eq!(m.name, "Option Bytes");
+        assert_eq!(m.segments.len(), 2);
+        assert_eq!(
+            m.segments[0].as_slice(),
+            &[Page {
+                address: 0x1fffc000,
+                size: 16,
+                permissions: Permissions {
+                    readable: true,
+                    erasable: false,
+                    writable: true,
+                },
+            }]
+        );
+        assert_eq!(m.segments[1][0].address, 0x1ffec000);
+        assert_eq!(
+            m.segments[1][0].permissions,
+            Permissions {
+                readable: true,
+                erasable: false,
+                writable: false,
+            }
+        );
+    }
+
+    #[test]
+    fn parsing_dfuse_memory_errors() {
+        assert!(matches!(
+            DfuseMemory::try_from("Internal Flash/0x08000000/04*016Kg"),
+            Err(Error::InvalidInterfaceString(_))
+        ));
+        assert!(matches!(
+            DfuseMemory::try_from("@Internal Flash/08000000/04*016Kg"),
+            Err(Error::ParseErrorAddress(_))
+        ));
+        assert!(matches!(
+            DfuseMemory::try_from("@Internal Flash/0x08000000/04*016Kz"),
+            Err(Error::InvalidPermissions(_))
+        ));
+        assert!(matches!(
+            DfuseMemory::try_from("@Internal Flash/0x08000000"),
+            Err(Error::InvalidInterfaceString(_))
+        ));
+    }
 }
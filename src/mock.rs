@@ -0,0 +1,367 @@
+//! A simulated device to test the state machines.
+
+use super::*;
+use core::convert::TryFrom;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::prelude::v1::*;
+
+/// Address of the memory of the device.
+pub(crate) const ADDRESS: u32 = 0x08000000;
+/// Transfer size of the device.
+pub(crate) const TRANSFER_SIZE: u16 = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum MockError {
+    #[error(transparent)]
+    Dfu(#[from] Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("request {0} stalled in state {1}")]
+    Stall(u8, State),
+}
+
+/// A request received by the device (`DFU_GETSTATUS` is not recorded).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Request {
+    Detach,
+    Dnload(u16, usize),
+    SetAddress(u32),
+    Erase(u32),
+    MassErase,
+    ReadUnprotect,
+    Upload(u16, usize),
+    ClrStatus,
+    GetState,
+    Abort,
+    UsbReset,
+    Reconnect,
+}
+
+/// A device following the DFU state machine, with the DfuSe commands if its DFU version is
+/// `0x011a`.
+pub(crate) struct Device {
+    pub(crate) functional_descriptor: functional_descriptor::FunctionalDescriptor,
+    /// Functional descriptor while the device runs its application.
+    pub(crate) runtime_functional_descriptor: Option<functional_descriptor::FunctionalDescriptor>,
+    pub(crate) memory_map: memory_layout::MemoryMap,
+    /// DfuSe commands returned by the Get command.
+    pub(crate) commands: Vec<u8>,
+    /// Vendor ID and product ID returned by [`DfuIo::device_ids`].
+    pub(crate) device_ids: Option<(u16, u16)>,
+    pub(crate) state: Cell<State>,
+    /// Status codes with their `iString` reported by the next downloads of data.
+    pub(crate) errors: RefCell<VecDeque<(u8, u8)>>,
+    /// Content of the memory from [`ADDRESS`] (DfuSe) or of the firmware (plain DFU).
+    pub(crate) memory: RefCell<Vec<u8>>,
+    pub(crate) requests: RefCell<Vec<Request>>,
+    /// Number of `DFU_GETSTATUS` answered in `dfuDNBUSY` after the next DfuSe command.
+    pub(crate) busy: Cell<u8>,
+    status: Cell<(u8, u8)>,
+    address_pointer: Cell<u32>,
+}
+
+impl Device {
+    pub(crate) fn new(protocol: Protocol) -> Self {
+        let memory = memory_layout::DfuseMemory::try_from("@Flash/0x08000000/04*001Kg,02*004Kg")
+            .unwrap()
+            .memory_map();
+
+        Self {
+            functional_descriptor: functional_descriptor::FunctionalDescriptor {
+                can_download: true,
+                can_upload: true,
+                manifestation_tolerant: true,
+                will_detach: false,
+                detach_timeout: 100,
+                transfer_size: TRANSFER_SIZE,
+                dfu_version: match protocol {
+                    Protocol::Dfu => (0x01, 0x10),
+                    Protocol::DfuSe => (0x01, 0x1a),
+                },
+            },
+            runtime_functional_descriptor: None,
+            memory_map: memory,
+            commands: vec![0x00, 0x21, 0x41, 0x92],
+            device_ids: None,
+            state: Cell::new(State::DfuIdle),
+            errors: Default::default(),
+            memory: Default::default(),
+            requests: Default::default(),
+            busy: Cell::new(0),
+            status: Cell::new((0, 0)),
+            address_pointer: Cell::new(ADDRESS),
+        }
+    }
+
+    /// Create a device running its application: it describes itself as a plain DFU device
+    /// without memory layout until it is switched to DFU mode.
+    pub(crate) fn runtime(protocol: Protocol) -> Self {
+        let device = Self::new(protocol);
+
+        Self {
+            runtime_functional_descriptor: Some(functional_descriptor::FunctionalDescriptor {
+                dfu_version: (0x01, 0x10),
+                ..device.functional_descriptor
+            }),
+            state: Cell::new(State::AppIdle),
+            ..device
+        }
+    }
+
+    /// Take the requests received so far.
+    pub(crate) fn take_requests(&self) -> Vec<Request> {
+        self.requests.take()
+    }
+
+    fn runtime_mode(&self) -> bool {
+        matches!(self.state.get(), State::AppIdle | State::AppDetach)
+    }
+
+    fn dfuse(&self) -> bool {
+        self.functional_descriptor.protocol() == Protocol::DfuSe
+    }
+
+    fn stall(&self, request: u8) -> MockError {
+        MockError::Stall(request, self.state.get())
+    }
+
+    /// Offset in the memory of a block of data.
+    fn offset(&self, block_num: u16) -> usize {
+        if self.dfuse() {
+            (self.address_pointer.get() - ADDRESS) as usize
+                + (block_num as usize - 2) * TRANSFER_SIZE as usize
+        } else {
+            block_num as usize * TRANSFER_SIZE as usize
+        }
+    }
+
+    fn download(&self, block_num: u16, buffer: &[u8]) -> Result<(), MockError> {
+        let state = self.state.get();
+
+        if buffer.is_empty() {
+            if state != State::DfuDnloadIdle {
+                return Err(self.stall(1));
+            }
+            self.requests
+                .borrow_mut()
+                .push(Request::Dnload(block_num, 0));
+            self.state.set(State::DfuManifestSync);
+            return Ok(());
+        }
+
+        if self.dfuse() && block_num == 0 {
+            let address = buffer
+                .get(1..5)
+                .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]));
+            let request = match (buffer[0], address) {
+                (0x21, Some(address)) => {
+                    self.address_pointer.set(address);
+                    Request::SetAddress(address)
+                }
+                (0x41, Some(address)) => Request::Erase(address),
+                (0x41, None) => Request::MassErase,
+                (0x92, None) => Request::ReadUnprotect,
+                _ => return Err(self.stall(1)),
+            };
+            self.requests.borrow_mut().push(request);
+            self.state.set(if self.busy.get() > 0 {
+                State::DfuDnbusy
+            } else {
+                State::DfuDnloadIdle
+            });
+            return Ok(());
+        }
+
+        self.requests
+            .borrow_mut()
+            .push(Request::Dnload(block_num, buffer.len()));
+        if let Some(error) = self.errors.borrow_mut().pop_front() {
+            self.status.set(error);
+            self.state.set(State::DfuError);
+            return Ok(());
+        }
+
+        let offset = self.offset(block_num);
+        let mut memory = self.memory.borrow_mut();
+        if memory.len() < offset + buffer.len() {
+            memory.resize(offset + buffer.len(), 0xff);
+        }
+        memory[offset..offset + buffer.len()].copy_from_slice(buffer);
+        self.state.set(State::DfuDnloadIdle);
+
+        Ok(())
+    }
+
+    fn upload(&self, block_num: u16, buffer: &mut [u8]) -> usize {
+        self.requests
+            .borrow_mut()
+            .push(Request::Upload(block_num, buffer.len()));
+
+        let data = if self.dfuse() && block_num == 0 {
+            self.commands.clone()
+        } else {
+            let memory = self.memory.borrow();
+            memory
+                .get(self.offset(block_num)..)
+                .unwrap_or_default()
+                .to_vec()
+        };
+        let n = data.len().min(buffer.len());
+        buffer[..n].copy_from_slice(&data[..n]);
+
+        // a short frame ends the transfer
+        self.state.set(if n < buffer.len() {
+            State::DfuIdle
+        } else {
+            State::DfuUploadIdle
+        });
+
+        n
+    }
+
+    fn state_code(state: State) -> u8 {
+        (0..=10).find(|code| State::from(*code) == state).unwrap()
+    }
+}
+
+impl DfuIo for &Device {
+    type Read = usize;
+    type Write = usize;
+    type Reset = ();
+    type Error = MockError;
+
+    fn read_control(
+        &self,
+        _request_type: u8,
+        request: u8,
+        value: u16,
+        buffer: &mut [u8],
+    ) -> Result<Self::Read, Self::Error> {
+        let state = self.state.get();
+
+        match request {
+            // DFU_UPLOAD
+            2 if matches!(state, State::DfuIdle | State::DfuUploadIdle) => {
+                Ok(self.upload(value, buffer))
+            }
+            // DFU_GETSTATUS
+            3 => {
+                let state = match state {
+                    State::DfuManifestSync => State::DfuManifest,
+                    State::DfuManifest => State::DfuIdle,
+                    State::DfuDnbusy if self.busy.get() > 0 => {
+                        self.busy.set(self.busy.get() - 1);
+                        State::DfuDnbusy
+                    }
+                    State::DfuDnbusy => State::DfuDnloadIdle,
+                    state => state,
+                };
+                let poll_timeout = u8::from(state == State::DfuDnbusy);
+                // the device cannot answer anymore once the manifestation started
+                self.state.set(
+                    if state == State::DfuManifest
+                        && !self.functional_descriptor.manifestation_tolerant
+                    {
+                        State::DfuManifestWaitReset
+                    } else {
+                        state
+                    },
+                );
+                let (status, i_string) = self.status.get();
+                buffer[..6].copy_from_slice(&[
+                    status,
+                    poll_timeout,
+                    0,
+                    0,
+                    Device::state_code(state),
+                    i_string,
+                ]);
+                Ok(6)
+            }
+            // DFU_GETSTATE
+            5 => {
+                self.requests.borrow_mut().push(Request::GetState);
+                buffer[0] = Device::state_code(state);
+                Ok(1)
+            }
+            _ => Err(self.stall(request)),
+        }
+    }
+
+    fn write_control(
+        &self,
+        _request_type: u8,
+        request: u8,
+        value: u16,
+        buffer: &[u8],
+    ) -> Result<Self::Write, Self::Error> {
+        let state = self.state.get();
+
+        match request {
+            // DFU_DETACH
+            0 if state == State::AppIdle => {
+                self.requests.borrow_mut().push(Request::Detach);
+                self.state.set(State::AppDetach);
+            }
+            // DFU_DNLOAD
+            1 if matches!(state, State::DfuIdle | State::DfuDnloadIdle) => {
+                self.download(value, buffer)?;
+            }
+            // DFU_CLRSTATUS
+            4 if !matches!(state, State::AppIdle | State::AppDetach) => {
+                self.requests.borrow_mut().push(Request::ClrStatus);
+                if state == State::DfuError {
+                    self.status.set((0, 0));
+                    self.state.set(State::DfuIdle);
+                }
+            }
+            // DFU_ABORT
+            6 if matches!(
+                state,
+                State::DfuIdle | State::DfuDnloadIdle | State::DfuUploadIdle
+            ) =>
+            {
+                self.requests.borrow_mut().push(Request::Abort);
+                self.state.set(State::DfuIdle);
+            }
+            _ => return Err(self.stall(request)),
+        }
+
+        Ok(buffer.len())
+    }
+
+    fn usb_reset(&self) -> Result<Self::Reset, Self::Error> {
+        self.requests.borrow_mut().push(Request::UsbReset);
+        Ok(())
+    }
+
+    fn reconnect(&self, _timeout: u64) -> Option<Result<Self, Self::Error>> {
+        self.requests.borrow_mut().push(Request::Reconnect);
+        self.state.set(State::DfuIdle);
+        Some(Ok(*self))
+    }
+
+    fn memory_layout(&self) -> &[memory_layout::Page] {
+        if self.runtime_mode() {
+            &[]
+        } else {
+            &self.memory_map
+        }
+    }
+
+    fn functional_descriptor(&self) -> &functional_descriptor::FunctionalDescriptor {
+        match &self.runtime_functional_descriptor {
+            Some(functional_descriptor) if self.runtime_mode() => functional_descriptor,
+            _ => &self.functional_descriptor,
+        }
+    }
+
+    fn device_ids(&self) -> Option<(u16, u16)> {
+        self.device_ids
+    }
+
+    fn string_descriptor(&self, index: u8) -> Option<StatusDescription> {
+        (index == 1).then(|| StatusDescription::new("Flash locked"))
+    }
+}
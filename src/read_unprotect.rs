@@ -0,0 +1,121 @@
+use super::*;
+
+const REQUEST_TYPE: u8 = 0b00100001;
+
+/// Starting point to remove the read protection of a DfuSe device.
+#[must_use]
+pub struct Start<'dfu, IO: DfuIo> {
+    pub(crate) dfu: &'dfu DfuSansIo<IO>,
+    pub(crate) aborted: bool,
+}
+
+impl<'dfu, IO: DfuIo> ChainedCommand for Start<'dfu, IO> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<abort::Step<'dfu, IO, Self, ReadUnprotect<'dfu, IO>>, Error>;
+
+    fn chain(
+        self,
+        get_status::GetStatusMessage {
+            status: _,
+            poll_timeout: _,
+            state,
+            index: _,
+        }: Self::Arg,
+    ) -> Self::Into {
+        let dfu = self.dfu;
+
+        abort::Step::new(
+            dfu,
+            state,
+            self.aborted,
+            || ReadUnprotect { dfu },
+            || Start { dfu, aborted: true },
+        )
+    }
+}
+
+/// Remove the read protection of the device.
+#[must_use]
+pub struct ReadUnprotect<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+}
+
+impl<'dfu, IO: DfuIo> ReadUnprotect<'dfu, IO> {
+    /// Send the Read Unprotect command to the device.
+    ///
+    /// The command is executed when the status is queried: the device then erases its whole
+    /// memory and resets itself.
+    pub fn read_unprotect(
+        self,
+    ) -> Result<
+        (
+            get_status::GetStatus<'dfu, IO, Unprotecting<'dfu, IO>>,
+            IO::Write,
+        ),
+        IO::Error,
+    > {
+        let next = get_status::GetStatus {
+            dfu: self.dfu,
+            chained_command: Unprotecting { dfu: self.dfu },
+        };
+        let res = self.dfu.io.write_control(
+            REQUEST_TYPE,
+            download::DFU_DNLOAD,
+            0,
+            &<[u8; 1]>::from(download::DownloadCommandReadUnprotect),
+        )?;
+
+        Ok((next, res))
+    }
+}
+
+/// The device is removing its read protection.
+#[must_use]
+pub struct Unprotecting<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+}
+
+impl<'dfu, IO: DfuIo> ChainedCommand for Unprotecting<'dfu, IO> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = reset::Reconnect<'dfu, IO, ()>;
+
+    fn chain(
+        self,
+        get_status::GetStatusMessage {
+            status: _,
+            poll_timeout,
+            state: _,
+            index: _,
+        }: Self::Arg,
+    ) -> Self::Into {
+        reset::Reconnect {
+            dfu: self.dfu,
+            timeout: poll_timeout,
+            chained_command: (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{Device, Request};
+
+    #[test]
+    fn read_unprotect() {
+        let device = Device::new(Protocol::DfuSe);
+        let mut dfu = sync::DfuSync::new(&device, mock::ADDRESS);
+
+        // the device resets itself once its memory is erased and is opened again
+        dfu.read_unprotect().unwrap();
+        assert_eq!(
+            device.take_requests(),
+            vec![
+                Request::ClrStatus,
+                Request::ReadUnprotect,
+                Request::Reconnect,
+            ]
+        );
+        assert_eq!(device.state.get(), State::DfuIdle);
+    }
+}
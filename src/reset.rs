@@ -16,3 +16,56 @@ impl<'dfu, IO: DfuIo, T> UsbReset<'dfu, IO, T> {
         (next, res)
     }
 }
+
+/// The device is going to leave the bus and re-enumerate.
+#[must_use]
+pub struct Reconnect<'dfu, IO: DfuIo, T> {
+    pub(crate) dfu: &'dfu DfuSansIo<IO>,
+    pub(crate) timeout: u64,
+    pub(crate) chained_command: T,
+}
+
+impl<'dfu, IO: DfuIo, T> Reconnect<'dfu, IO, T> {
+    /// Time, in milliseconds, that the device announced to take before leaving the bus.
+    pub fn timeout(&self) -> u64 {
+        self.timeout
+    }
+
+    /// Wait for the device to re-enumerate and open it again (see [`DfuIo::reconnect`]).
+    ///
+    /// Returns `None` if the IO cannot open the device again.
+    pub fn reconnect(self) -> (T, Option<Result<IO, IO::Error>>) {
+        let res = self.dfu.io.reconnect(self.timeout);
+        let next = self.chained_command;
+
+        (next, res)
+    }
+}
+
+/// A step when the device leaves the bus.
+#[allow(missing_docs)]
+pub enum Step<'dfu, IO: DfuIo, T> {
+    /// The device must be reset by the host.
+    UsbReset(UsbReset<'dfu, IO, Reconnect<'dfu, IO, T>>),
+    Reconnect(Reconnect<'dfu, IO, T>),
+}
+
+impl<'dfu, IO: DfuIo, T> Step<'dfu, IO, T> {
+    /// The host must reset the device unless it detaches itself (`bitWillDetach`).
+    pub(crate) fn new(dfu: &'dfu DfuSansIo<IO>, timeout: u64, chained_command: T) -> Self {
+        let reconnect = Reconnect {
+            dfu,
+            timeout,
+            chained_command,
+        };
+
+        if dfu.io.functional_descriptor().will_detach {
+            Step::Reconnect(reconnect)
+        } else {
+            Step::UsbReset(UsbReset {
+                dfu,
+                chained_command: reconnect,
+            })
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use displaydoc::Display;
+#[cfg(any(feature = "std", test))]
+use std::prelude::v1::*;
+#[cfg(any(feature = "std", test))]
+use thiserror::Error;
+
+/// Length of the DFU suffix defined by DFU 1.1.
+pub const SUFFIX_LEN: usize = 16;
+const SIGNATURE: &[u8] = b"UFD";
+
+/// Error while reading a DFU suffix.
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(any(feature = "std", test), derive(Error))]
+#[allow(missing_docs)]
+pub enum Error {
+    /// invalid suffix length: {0}
+    InvalidLength(u8),
+    /// invalid CRC (got: {got:#010x}, expected: {expected:#010x})
+    InvalidCrc { got: u32, expected: u32 },
+}
+
+/// CRC-32 of a file as stored in its DFU suffix (without the final inversion).
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0xffffffff, bytes)
+}
+
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// The DFU suffix appended to a firmware file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Suffix {
+    /// Release number of the device (`bcdDevice`), `0xffff` for any.
+    pub device: u16,
+    /// Product ID, `0xffff` for any.
+    pub product_id: u16,
+    /// Vendor ID, `0xffff` for any.
+    pub vendor_id: u16,
+    /// Release number of the DFU specification (`bcdDFU`).
+    pub dfu_version: u16,
+    /// Length of the suffix (`bLength`).
+    pub length: u8,
+    /// CRC of the file (`dwCRC`).
+    pub crc: u32,
+}
+
+impl Suffix {
+    /// Create a suffix for the device: its CRC is computed when written.
+    ///
+    /// Use `0xffff` for the IDs or the release number to target any device.
+    pub fn new(vendor_id: u16, product_id: u16, device: u16, dfu_version: u16) -> Self {
+        Self {
+            device,
+            product_id,
+            vendor_id,
+            dfu_version,
+            length: SUFFIX_LEN as u8,
+            crc: 0,
+        }
+    }
+
+    /// Returns the bytes of the suffix to append to `firmware` with the CRC of the resulting file.
+    pub fn to_bytes(&self, firmware: &[u8]) -> [u8; SUFFIX_LEN] {
+        let mut bytes = [0; SUFFIX_LEN];
+        bytes[0..2].copy_from_slice(&self.device.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.product_id.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.vendor_id.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.dfu_version.to_le_bytes());
+        bytes[8..11].copy_from_slice(SIGNATURE);
+        bytes[11] = SUFFIX_LEN as u8;
+        let crc = crc32_update(crc32(firmware), &bytes[..12]);
+        bytes[12..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Read the suffix at the end of a file without validating it.
+    ///
+    /// Returns `None` if the file does not end with a suffix.
+    pub fn read(file: &[u8]) -> Option<Self> {
+        let suffix = file.get(file.len().checked_sub(SUFFIX_LEN)?..)?;
+        if &suffix[8..11] != SIGNATURE {
+            return None;
+        }
+
+        let u16_at = |at: usize| u16::from_le_bytes([suffix[at], suffix[at + 1]]);
+
+        Some(Self {
+            device: u16_at(0),
+            product_id: u16_at(2),
+            vendor_id: u16_at(4),
+            dfu_version: u16_at(6),
+            length: suffix[11],
+            crc: u32::from_le_bytes([suffix[12], suffix[13], suffix[14], suffix[15]]),
+        })
+    }
+
+    /// Returns `true` if the file targets a device with these vendor and product IDs.
+    pub fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        (self.vendor_id == 0xffff || self.vendor_id == vendor_id)
+            && (self.product_id == 0xffff || self.product_id == product_id)
+    }
+}
+
+/// Detect the DFU suffix of a file, validate it and strip it.
+///
+/// The file is returned unchanged if it does not end with a suffix.
+pub fn strip(file: &[u8]) -> Result<(&[u8], Option<Suffix>), Error> {
+    let (firmware, suffix) = split(file)?;
+
+    if let Some(suffix) = suffix {
+        let crc = crc32(&file[..file.len() - 4]);
+        if crc != suffix.crc {
+            return Err(Error::InvalidCrc {
+                got: suffix.crc,
+                expected: crc,
+            });
+        }
+    }
+
+    Ok((firmware, suffix))
+}
+
+fn split(file: &[u8]) -> Result<(&[u8], Option<Suffix>), Error> {
+    let suffix = match Suffix::read(file) {
+        Some(suffix) => suffix,
+        None => return Ok((file, None)),
+    };
+
+    if (suffix.length as usize) < SUFFIX_LEN || suffix.length as usize > file.len() {
+        return Err(Error::InvalidLength(suffix.length));
+    }
+
+    Ok((&file[..file.len() - suffix.length as usize], Some(suffix)))
+}
+
+/// Append a suffix to a firmware file, replacing its current suffix if any.
+///
+/// The current suffix is replaced even if its CRC is invalid.
+#[cfg(any(feature = "std", test))]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+pub fn add(file: &[u8], suffix: Suffix) -> Result<Vec<u8>, Error> {
+    let (firmware, _) = split(file)?;
+    let mut bytes = Vec::with_capacity(firmware.len() + SUFFIX_LEN);
+    bytes.extend_from_slice(firmware);
+    bytes.extend_from_slice(&suffix.to_bytes(firmware));
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stripping() {
+        assert_eq!(crc32(b"123456789"), !0xcbf43926);
+
+        let mut file = b"firmware".to_vec();
+        assert_eq!(strip(&file).unwrap(), (&b"firmware"[..], None));
+
+        file.extend_from_slice(&[0x00, 0x02, 0x11, 0xdf, 0x83, 0x04, 0x00, 0x01]);
+        file.extend_from_slice(b"UFD\x10");
+        let crc = crc32(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+
+        let (firmware, suffix) = strip(&file).unwrap();
+        assert_eq!(firmware, b"firmware");
+        assert_eq!(
+            suffix,
+            Some(Suffix {
+                device: 0x0200,
+                product_id: 0xdf11,
+                vendor_id: 0x0483,
+                dfu_version: 0x0100,
+                length: 16,
+                crc,
+            })
+        );
+        assert!(suffix.unwrap().matches(0x0483, 0xdf11));
+        assert!(!suffix.unwrap().matches(0x0483, 0x0001));
+
+        file[0] = b'F';
+        assert!(matches!(strip(&file), Err(Error::InvalidCrc { .. })));
+    }
+
+    #[test]
+    fn adding() {
+        let suffix = Suffix::new(0x0483, 0xdf11, 0x0200, 0x0100);
+        let file = add(b"firmware", suffix).unwrap();
+        let (firmware, read) = strip(&file).unwrap();
+        assert_eq!(firmware, b"firmware");
+        assert_eq!(
+            read,
+            Some(Suffix {
+                crc: crc32(&file[..file.len() - 4]),
+                ..suffix
+            })
+        );
+
+        // the current suffix is replaced
+        let file = add(&file, Suffix::new(0xffff, 0xffff, 0xffff, 0x0100)).unwrap();
+        let (firmware, read) = strip(&file).unwrap();
+        assert_eq!(firmware, b"firmware");
+        assert_eq!(read.unwrap().vendor_id, 0xffff);
+    }
+}
@@ -3,6 +3,61 @@ use std::convert::TryFrom;
 use std::io::Cursor;
 use std::prelude::v1::*;
 
+/// Wait for the state expected by a [`get_status::WaitState`] and return its chained command.
+///
+/// The device is opened again when it leaves the bus after the manifestation.
+macro_rules! wait_status {
+    ($buffer:expr, $cmd:expr) => {{
+        let mut cmd = $cmd;
+        loop {
+            cmd = match cmd.next() {
+                get_status::Step::Break(cmd) => break Waited::Ready(cmd),
+                get_status::Step::Recover(cmd) => {
+                    let (cmd, _) = cmd.clear()?;
+                    break Waited::Ready(cmd);
+                }
+                get_status::Step::Wait(cmd, poll_timeout) => {
+                    std::thread::sleep(std::time::Duration::from_millis(poll_timeout));
+                    Self::get_status(cmd, &mut $buffer[..])?
+                }
+                get_status::Step::ManifestWaitReset(step) => {
+                    break Waited::Left(Self::reconnect(step)?);
+                }
+            };
+        }
+    }};
+}
+
+/// Outcome of [`wait_status`].
+enum Waited<T, IO> {
+    /// The state has been reached.
+    Ready(T),
+    /// The device left the bus after the manifestation and has been opened again, if it could be.
+    Left(Option<IO>),
+}
+
+impl<T, IO> Waited<T, IO> {
+    /// Returns the chained command when the device is not expected to leave the bus.
+    fn ready(self, expected: State) -> Result<T, Error> {
+        match self {
+            Waited::Ready(cmd) => Ok(cmd),
+            Waited::Left(_) => Err(Error::InvalidState {
+                got: State::DfuManifestWaitReset,
+                expected,
+            }),
+        }
+    }
+}
+
+/// How a download ended.
+enum Downloaded<IO> {
+    /// The firmware has been downloaded and the device has been opened again if it left the bus.
+    Done(Option<IO>),
+    /// The device has been switched to DFU mode and opened again: the download must be started
+    /// again.
+    Detached(IO),
+}
+
 /// Generic synchronous implementation of DFU.
 #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
 pub struct DfuSync<IO, E>
@@ -13,6 +68,8 @@ where
     dfu: DfuSansIo<IO>,
     buffer: Vec<u8>,
     progress: Option<Box<dyn FnMut(usize)>>,
+    verify: bool,
+    check_device: bool,
 }
 
 impl<IO, E> DfuSync<IO, E>
@@ -28,6 +85,8 @@ where
             dfu: DfuSansIo::new(io, address),
             buffer: vec![0x00; transfer_size],
             progress: None,
+            verify: false,
+            check_device: false,
         }
     }
 
@@ -39,12 +98,48 @@ where
         }
     }
 
+    /// Read back the firmware after downloading it and compare it with the data downloaded.
+    ///
+    /// The progress of the verification is reported to the progress closure. The download fails
+    /// before anything is written if the firmware cannot be read back.
+    pub fn with_verify(self, verify: bool) -> Self {
+        Self { verify, ..self }
+    }
+
+    /// Refuse the firmware files whose DFU suffix targets another device.
+    ///
+    /// The check is skipped if the IO does not provide the IDs of the device (see
+    /// [`DfuIo::device_ids`]).
+    pub fn with_device_check(self, check_device: bool) -> Self {
+        Self {
+            check_device,
+            ..self
+        }
+    }
+
     /// Override the address.
     pub fn override_address(self, address: u32) -> Self {
-        let (io, _) = self.dfu.into_parts();
+        Self {
+            dfu: DfuSansIo {
+                address,
+                ..self.dfu
+            },
+            ..self
+        }
+    }
 
+    /// Set what to do when the device reports an error during a download.
+    pub fn with_recovery_policy(self, recovery_policy: RecoveryPolicy) -> Self {
         Self {
-            dfu: DfuSansIo::new(io, address),
+            dfu: self.dfu.with_recovery_policy(recovery_policy),
+            ..self
+        }
+    }
+
+    /// Override the protocol inferred from the functional descriptor.
+    pub fn override_protocol(self, protocol: Protocol) -> Self {
+        Self {
+            dfu: self.dfu.with_protocol(protocol),
             ..self
         }
     }
@@ -56,7 +151,14 @@ where
     E: From<std::io::Error> + From<Error>,
 {
     /// Download a slice to on to the device.
+    ///
+    /// The DFU suffix of the firmware file, if any, is validated and is not downloaded.
     pub fn download_from_slice(&mut self, slice: &[u8]) -> Result<(), IO::Error> {
+        let (slice, suffix) = suffix::strip(slice).map_err(Error::from)?;
+        if let Some(suffix) = suffix {
+            self.check_device(&suffix)?;
+        }
+
         let length = slice.len();
         let cursor = Cursor::new(slice);
 
@@ -67,8 +169,11 @@ where
     }
 
     /// Download a firmware into the device.
+    ///
+    /// A device running its application is detached first and opened again in DFU mode with
+    /// [`DfuIo::reconnect`], as is a device that leaves the bus after the manifestation.
     pub fn download<R: std::io::Read>(&mut self, reader: R, length: u32) -> Result<(), IO::Error> {
-        use std::io::BufRead;
+        use std::io::{BufRead, Read};
 
         let transfer_size = self.dfu.io.functional_descriptor().transfer_size as usize;
         let mut reader = std::io::BufReader::with_capacity(transfer_size, reader);
@@ -77,56 +182,751 @@ where
             return Ok(());
         }
 
-        macro_rules! wait_status {
-            ($cmd:expr) => {{
-                let mut cmd = $cmd;
-                loop {
-                    cmd = match cmd.next() {
-                        get_status::Step::Break(cmd) => break cmd,
-                        get_status::Step::Wait(cmd, poll_timeout) => {
-                            std::thread::sleep(std::time::Duration::from_millis(poll_timeout));
-                            let (cmd, n) = cmd.get_status(&mut self.buffer)?;
-                            cmd.chain(&self.buffer[..n])?
-                        }
-                        get_status::Step::ManifestWaitReset(None) => return Ok(()),
-                        get_status::Step::ManifestWaitReset(Some(cmd)) => {
-                            let (_, res) = cmd.reset();
-                            res?;
-                            return Ok(());
-                        }
-                    };
+        let mut written = Vec::new();
+        let verify = self.verify;
+        let segment = download::Segment {
+            address: self.dfu.address,
+            length,
+        };
+
+        self.download_with(&[segment], |_, chunk| {
+            let mut n = 0;
+            while n < chunk.len() {
+                match reader.read(&mut chunk[n..]) {
+                    Ok(0) => break,
+                    Ok(len) => n += len,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
                 }
-            }};
+            }
+            if verify {
+                written.extend_from_slice(&chunk[..n]);
+            }
+            Ok(n)
+        })?;
+
+        if verify {
+            written.truncate(length as usize);
+            self.verify(&[(self.dfu.address, &written)])?;
+        }
+
+        Ok(())
+    }
+
+    /// Download a firmware into a DfuSe device and start it.
+    ///
+    /// The application is started from the download address.
+    pub fn download_and_leave<R: std::io::Read>(
+        &mut self,
+        reader: R,
+        length: u32,
+    ) -> Result<(), IO::Error> {
+        // fail before downloading anything if the device cannot leave the DFU mode, the protocol
+        // of a device running its application is only known once it is in DFU mode
+        if self.state()? != State::AppIdle {
+            let _ = self.dfu.leave(self.dfu.address)?;
         }
+        self.download(reader, length)?;
+        self.leave(self.dfu.address)
+    }
 
-        let cmd = self.dfu.download(length)?;
+    /// Leave the DFU mode of a DfuSe device and jump to the application at `address`.
+    ///
+    /// The device is opened again with [`DfuIo::reconnect`] once it re-enumerated.
+    pub fn leave(&mut self, address: u32) -> Result<(), IO::Error> {
+        let cmd = self.dfu.leave(address)?;
         let (cmd, _) = cmd.clear()?;
-        let (cmd, n) = cmd.get_status(&mut self.buffer)?;
-        let mut download_loop = cmd.chain(&self.buffer[..n])??;
+        let cmd = Self::get_status(cmd, &mut self.buffer)??;
+        let (cmd, _) = cmd.set_address()?;
+        let cmd = wait_status!(self.buffer, cmd).ready(State::DfuDnloadIdle)?;
+        let (cmd, _) = cmd.leave()?;
+        let cmd = Self::get_status(cmd, &mut self.buffer)?;
+        if let Some(io) = Self::reconnect(reset::Step::Reconnect(cmd))? {
+            self.replace_io(io);
+        }
+
+        Ok(())
+    }
+
+    /// Download segments of a firmware into the device, each one at its own address.
+    ///
+    /// Only the pages overlapping the segments are erased. The segments must be sorted by address
+    /// and must not overlap.
+    pub fn download_segments(&mut self, segments: &[(u32, &[u8])]) -> Result<(), IO::Error> {
+        let download_segments = segments
+            .iter()
+            .map(|(address, data)| {
+                Ok(download::Segment {
+                    address: *address,
+                    length: u32::try_from(data.len()).map_err(|_| Error::OutOfCapabilities)?,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        self.download_with(&download_segments, |address, chunk| {
+            let data = segments
+                .iter()
+                .find_map(|(start, data)| {
+                    data.get(address.checked_sub(*start)? as usize..)
+                        .filter(|data| !data.is_empty())
+                })
+                .unwrap_or_default();
+            let n = data.len().min(chunk.len());
+            chunk[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        })?;
+
+        if self.verify {
+            self.verify(segments)?;
+        }
+
+        Ok(())
+    }
+
+    /// Download every image element of the target of a DfuSe file (`.dfu`) at its own address.
+    ///
+    /// The target is the one of the alternate setting `alt_setting`.
+    pub fn download_dfuse_file(&mut self, file: &[u8], alt_setting: u8) -> Result<(), IO::Error> {
+        let file = dfuse_file::DfuseFile::parse(file).map_err(Error::from)?;
+        self.check_device(&file.suffix())?;
+        let target = file.target(alt_setting).map_err(Error::from)?;
+        let mut segments = target.elements().collect::<Vec<_>>();
+        segments.sort_by_key(|(address, _)| *address);
+
+        self.download_segments(&segments)
+    }
+
+    /// Download the data of an Intel HEX file (`.hex`), each contiguous segment at its own
+    /// address.
+    ///
+    /// The gaps between the segments are left untouched.
+    pub fn download_ihex(&mut self, src: &str) -> Result<(), IO::Error> {
+        let ihex = ihex::IntelHex::try_from(src).map_err(Error::from)?;
+        let segments = ihex.segments().collect::<Vec<_>>();
+
+        self.download_segments(&segments)
+    }
+
+    /// Upload segments back from the device and compare them with the data downloaded.
+    ///
+    /// The first differing address and the number of differing bytes are reported with
+    /// [`Error::VerifyMismatch`].
+    pub fn verify(&mut self, segments: &[(u32, &[u8])]) -> Result<(), IO::Error> {
+        for (address, data) in segments {
+            let length = u32::try_from(data.len()).map_err(|_| Error::OutOfCapabilities)?;
+            let mut uploaded = Vec::with_capacity(data.len());
+            match self.dfu.protocol {
+                Protocol::Dfu => self.upload(&mut uploaded, length)?,
+                Protocol::DfuSe => self.upload_dfuse(&mut uploaded, *address, length)?,
+            };
+
+            let mut mismatches = data
+                .iter()
+                .enumerate()
+                .filter(|(i, byte)| uploaded.get(*i) != Some(byte))
+                .map(|(i, _)| i);
+            if let Some(first) = mismatches.next() {
+                return Err(Error::VerifyMismatch {
+                    address: address.saturating_add(first as u32),
+                    count: 1 + mismatches.count() as u32,
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The suffix of a firmware file must target the device if the check is enabled.
+    fn check_device(&self, suffix: &suffix::Suffix) -> Result<(), Error> {
+        if let (true, Some((vendor_id, product_id))) = (self.check_device, self.dfu.io.device_ids())
+        {
+            if !suffix.matches(vendor_id, product_id) {
+                return Err(Error::DeviceMismatch {
+                    vendor_id: suffix.vendor_id,
+                    product_id: suffix.product_id,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A plain DFU device must still be in DFU mode after the manifestation to be verified and the
+    /// pages of a DfuSe device receiving the segments must be readable.
+    fn check_verify(&self, segments: &[download::Segment]) -> Result<(), Error> {
+        let func_desc = self.dfu.io.functional_descriptor();
+
+        if !self.verify {
+            return Ok(());
+        }
+        if !func_desc.can_upload
+            || self.dfu.protocol == Protocol::Dfu && !func_desc.manifestation_tolerant
+        {
+            return Err(Error::VerifyNotSupported);
+        }
+
+        if self.dfu.protocol == Protocol::DfuSe {
+            for segment in segments {
+                if let Some(page) = memory_layout::pages_overlapping(
+                    self.dfu.io.memory_layout(),
+                    segment.address,
+                    segment.end()?,
+                )
+                .find(|page| !page.permissions.readable)
+                {
+                    return Err(Error::NotReadable(page.address));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Query the status of the device and chain it into the next command.
+    ///
+    /// The error reported by the device is described with [`DfuIo::string_descriptor`].
+    fn get_status<T: ChainedCommand<Arg = get_status::GetStatusMessage>>(
+        cmd: get_status::GetStatus<'_, IO, T>,
+        buffer: &mut [u8],
+    ) -> Result<T::Into, IO::Error> {
+        let io = &cmd.dfu.io;
+        let (cmd, n) = cmd.get_status(buffer)?;
+
+        Ok(cmd.chain(&buffer[..n]).map_err(|err| err.describe(io))?)
+    }
+
+    /// Query the status of the device to start a command, aborting the transfer of an interrupted
+    /// session first.
+    fn start<'dfu, S, T>(
+        cmd: get_status::GetStatus<'dfu, IO, S>,
+        buffer: &mut [u8],
+    ) -> Result<T, IO::Error>
+    where
+        S: ChainedCommand<
+            Arg = get_status::GetStatusMessage,
+            Into = Result<abort::Step<'dfu, IO, S, T>, Error>,
+        >,
+    {
+        let mut cmd = cmd;
+
+        loop {
+            cmd = match Self::get_status(cmd, buffer)?? {
+                abort::Step::Ready(cmd) => return Ok(cmd),
+                abort::Step::Abort(cmd) => cmd.abort()?.0,
+            };
+        }
+    }
+
+    /// Reset the device if needed and open it again once it re-enumerated.
+    ///
+    /// Returns `None` after waiting for the device to leave the bus if the IO cannot open it
+    /// again.
+    fn reconnect(step: reset::Step<'_, IO, ()>) -> Result<Option<IO>, IO::Error> {
+        let cmd = match step {
+            reset::Step::UsbReset(cmd) => {
+                let (cmd, res) = cmd.reset();
+                res?;
+                cmd
+            }
+            reset::Step::Reconnect(cmd) => cmd,
+        };
+        let timeout = cmd.timeout();
+
+        match cmd.reconnect() {
+            (_, Some(res)) => res.map(Some),
+            (_, None) => {
+                std::thread::sleep(std::time::Duration::from_millis(timeout));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Use the device opened again after it re-enumerated.
+    fn replace_io(&mut self, io: IO) {
+        let transfer_size = io.functional_descriptor().transfer_size as usize;
+
+        self.buffer.resize(transfer_size, 0x00);
+        self.dfu.replace_io(io);
+    }
+
+    /// Download the segments, starting again once a device running its application has been
+    /// switched to DFU mode.
+    fn download_with(
+        &mut self,
+        segments: &[download::Segment],
+        mut read_chunk: impl FnMut(u32, &mut [u8]) -> std::io::Result<usize>,
+    ) -> Result<(), IO::Error> {
+        let mut detached = false;
+
+        loop {
+            self.check_verify(segments)?;
+            let cmd = self.dfu.download_segments(segments)?;
+            match Self::download_loop(
+                cmd,
+                &mut self.buffer,
+                &mut self.progress,
+                detached,
+                &mut read_chunk,
+            )? {
+                Downloaded::Done(io) => {
+                    if let Some(io) = io {
+                        self.replace_io(io);
+                    }
+                    return Ok(());
+                }
+                Downloaded::Detached(io) => {
+                    self.replace_io(io);
+                    detached = true;
+                }
+            }
+        }
+    }
+
+    fn download_loop(
+        cmd: get_status::GetStatus<'_, IO, download::Start<'_, IO>>,
+        buffer: &mut [u8],
+        progress: &mut Option<Box<dyn FnMut(usize)>>,
+        detached: bool,
+        mut read_chunk: impl FnMut(u32, &mut [u8]) -> std::io::Result<usize>,
+    ) -> Result<Downloaded<IO>, IO::Error> {
+        let mut chunk = vec![0x00; buffer.len()];
+        // the last chunk is kept to be sent again when the device asks for a retry
+        let mut chunk_read: Option<(u32, usize)> = None;
+        let mut download_loop = Self::get_status(cmd, buffer)??;
 
         loop {
             download_loop = match download_loop.next() {
-                download::Step::Break => break,
+                download::Step::Break => return Ok(Downloaded::Done(None)),
+                download::Step::Abort(cmd) => {
+                    let (cmd, _) = cmd.abort()?;
+                    Self::get_status(cmd, buffer)??
+                }
+                download::Step::ClearStatus(cmd) => {
+                    let (cmd, _) = cmd.clear()?;
+                    Self::get_status(cmd, buffer)??
+                }
+                // the device is still running its application after being detached
+                download::Step::Detach(_) if detached => {
+                    return Err(Error::InvalidState {
+                        got: State::AppIdle,
+                        expected: State::DfuIdle,
+                    }
+                    .into())
+                }
+                download::Step::Detach(cmd) => {
+                    let (cmd, _) = cmd.detach()?;
+                    let io = Self::reconnect(cmd.next())?.ok_or(Error::ReconnectRequired)?;
+                    return Ok(Downloaded::Detached(io));
+                }
                 download::Step::Erase(cmd) => {
                     let (cmd, _) = cmd.erase()?;
-                    wait_status!(cmd)
+                    wait_status!(buffer, cmd).ready(State::DfuDnloadIdle)?
                 }
                 download::Step::SetAddress(cmd) => {
                     let (cmd, _) = cmd.set_address()?;
-                    wait_status!(cmd)
+                    wait_status!(buffer, cmd).ready(State::DfuDnloadIdle)?
                 }
                 download::Step::DownloadChunk(cmd) => {
-                    let chunk = reader.fill_buf()?;
-                    let (cmd, n) = cmd.download(chunk)?;
-                    reader.consume(n);
-                    if let Some(progress) = self.progress.as_mut() {
+                    let len = match chunk_read {
+                        Some((address, len)) if address == cmd.address() => len,
+                        _ => read_chunk(cmd.address(), &mut chunk)?,
+                    };
+                    chunk_read = Some((cmd.address(), len));
+                    let (cmd, n) = cmd.download(&chunk[..len])?;
+                    if let Some(progress) = progress.as_mut() {
                         progress(n);
                     }
-                    wait_status!(cmd)
+                    wait_status!(buffer, cmd).ready(State::DfuDnloadIdle)?
+                }
+                download::Step::Manifest(cmd) => {
+                    let (cmd, _) = cmd.manifest()?;
+                    match wait_status!(buffer, cmd) {
+                        Waited::Ready(cmd) => cmd,
+                        Waited::Left(io) => return Ok(Downloaded::Done(io)),
+                    }
                 }
             }
         }
+    }
+
+    /// Query the state of the device without changing it.
+    pub fn state(&mut self) -> Result<State, IO::Error> {
+        let cmd = self.dfu.get_state();
+        let (cmd, n) = cmd.get_state(&mut self.buffer)?;
+        let state = cmd.chain(&self.buffer[..n])?;
+
+        Ok(state)
+    }
+
+    /// Query the commands supported by a DfuSe device.
+    ///
+    /// The commands are remembered to fail early when a download or an erase requires a command
+    /// that the device does not support.
+    pub fn get_commands(&mut self) -> Result<get_commands::Commands, IO::Error> {
+        let cmd = self.dfu.get_commands()?;
+        let (cmd, _) = cmd.clear()?;
+        let cmd = Self::start(cmd, &mut self.buffer)?;
+        let (cmd, n) = cmd.upload(&mut self.buffer)?;
+        let commands = cmd.chain(&self.buffer[..n])?;
+        self.dfu.commands = Some(commands);
+
+        Ok(commands)
+    }
+
+    /// Erase the whole memory of a DfuSe device.
+    pub fn mass_erase(&mut self) -> Result<(), IO::Error> {
+        let cmd = self.dfu.mass_erase()?;
+        let (cmd, _) = cmd.clear()?;
+        let cmd = Self::start(cmd, &mut self.buffer)?;
+        let (cmd, _) = cmd.mass_erase()?;
+        wait_status!(self.buffer, cmd).ready(State::DfuDnloadIdle)?;
+
+        Ok(())
+    }
+
+    /// Remove the read protection of a DfuSe device.
+    ///
+    /// The device erases its whole memory and resets itself: it is opened again with
+    /// [`DfuIo::reconnect`].
+    pub fn read_unprotect(&mut self) -> Result<(), IO::Error> {
+        let cmd = self.dfu.read_unprotect()?;
+        let (cmd, _) = cmd.clear()?;
+        let cmd = Self::start(cmd, &mut self.buffer)?;
+        let (cmd, _) = cmd.read_unprotect()?;
+        let cmd = Self::get_status(cmd, &mut self.buffer)?;
+        if let Some(io) = Self::reconnect(reset::Step::Reconnect(cmd))? {
+            self.replace_io(io);
+        }
 
         Ok(())
     }
+
+    /// Upload a firmware from the device into a vector.
+    pub fn upload_to_vec(&mut self, length: u32) -> Result<Vec<u8>, IO::Error> {
+        let mut vec = Vec::with_capacity(length as usize);
+        self.upload(&mut vec, length)?;
+        Ok(vec)
+    }
+
+    /// Upload a firmware from the device.
+    ///
+    /// Returns the number of bytes read, which may be less than `length` if the device has less
+    /// data available. With DfuSe, the memory is read from the address of this instance.
+    pub fn upload<W: std::io::Write>(
+        &mut self,
+        writer: W,
+        length: u32,
+    ) -> Result<usize, IO::Error> {
+        let cmd = self.dfu.upload(length)?;
+        Self::upload_with(cmd, &mut self.buffer, &mut self.progress, writer)
+    }
+
+    /// Upload the memory window `[address, address + length)` from a DfuSe device.
+    ///
+    /// Returns the number of bytes read.
+    pub fn upload_dfuse<W: std::io::Write>(
+        &mut self,
+        writer: W,
+        address: u32,
+        length: u32,
+    ) -> Result<usize, IO::Error> {
+        let cmd = self.dfu.upload_dfuse(address, length)?;
+        Self::upload_with(cmd, &mut self.buffer, &mut self.progress, writer)
+    }
+
+    fn upload_with<W: std::io::Write>(
+        cmd: get_status::ClearStatus<'_, IO, get_status::GetStatus<'_, IO, upload::Start<'_, IO>>>,
+        buffer: &mut [u8],
+        progress: &mut Option<Box<dyn FnMut(usize)>>,
+        mut writer: W,
+    ) -> Result<usize, IO::Error> {
+        let (cmd, _) = cmd.clear()?;
+        let mut upload_loop = Self::get_status(cmd, buffer)??;
+        let mut copied = 0;
+
+        loop {
+            upload_loop = match upload_loop.next() {
+                upload::Step::Break => break,
+                upload::Step::Abort(cmd) => {
+                    let (cmd, _) = cmd.abort()?;
+                    Self::get_status(cmd, buffer)??
+                }
+                upload::Step::SetAddress(cmd) => {
+                    let (cmd, _) = cmd.set_address()?;
+                    let cmd = wait_status!(buffer, cmd).ready(State::DfuDnloadIdle)?;
+                    let (cmd, _) = cmd.abort()?;
+                    cmd
+                }
+                upload::Step::UploadChunk(cmd, poll_timeout) => {
+                    std::thread::sleep(std::time::Duration::from_millis(poll_timeout));
+                    let (cmd, n) = cmd.upload(buffer)?;
+                    writer.write_all(&buffer[..n])?;
+                    copied += n;
+                    if let Some(progress) = progress.as_mut() {
+                        progress(n);
+                    }
+                    cmd.chain(n)?
+                }
+                upload::Step::Finish(cmd) => {
+                    let (cmd, _) = cmd.abort()?;
+                    cmd
+                }
+            }
+        }
+
+        Ok(copied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{Device, Request};
+
+    #[test]
+    fn download_and_leave_verified() {
+        let device = Device::new(Protocol::DfuSe);
+        let firmware = vec![0x42; 300];
+        let mut dfu = DfuSync::new(&device, mock::ADDRESS).with_verify(true);
+
+        // the device is back in dfuIDLE after the verification and is opened again once it left
+        // the DFU mode
+        dfu.download_and_leave(firmware.as_slice(), 300).unwrap();
+        let requests = device.take_requests();
+        assert_eq!(
+            requests[requests.len() - 5..],
+            [
+                Request::Abort,
+                Request::ClrStatus,
+                Request::SetAddress(mock::ADDRESS),
+                Request::Dnload(2, 0),
+                Request::Reconnect,
+            ]
+        );
+
+        let device = Device::new(Protocol::Dfu);
+        let mut dfu = DfuSync::new(&device, mock::ADDRESS);
+        assert!(matches!(
+            dfu.download_and_leave(firmware.as_slice(), 300),
+            Err(mock::MockError::Dfu(Error::DfuseRequired))
+        ));
+        assert_eq!(device.take_requests(), vec![Request::GetState]);
+
+        // the device running its application describes itself as a plain DFU device
+        let device = Device::runtime(Protocol::DfuSe);
+        let mut dfu = DfuSync::new(&device, mock::ADDRESS);
+        dfu.download_and_leave(firmware.as_slice(), 300).unwrap();
+        let requests = device.take_requests();
+        assert_eq!(
+            requests[..4],
+            [
+                Request::GetState,
+                Request::Detach,
+                Request::UsbReset,
+                Request::Reconnect,
+            ]
+        );
+        assert_eq!(
+            requests[requests.len() - 3..],
+            [
+                Request::SetAddress(mock::ADDRESS),
+                Request::Dnload(2, 0),
+                Request::Reconnect,
+            ]
+        );
+        assert_eq!(device.memory.borrow()[..300], firmware[..]);
+    }
+
+    #[test]
+    fn get_commands() {
+        let mut device = Device::new(Protocol::DfuSe);
+        let mut dfu = DfuSync::new(&device, mock::ADDRESS);
+        let commands = dfu.get_commands().unwrap();
+        assert_eq!(
+            commands.iter().collect::<Vec<_>>(),
+            [0x00, 0x21, 0x41, 0x92]
+        );
+        assert_eq!(
+            device.take_requests(),
+            vec![Request::ClrStatus, Request::Upload(0, 256)]
+        );
+
+        // the download fails before anything is written when the erase is not supported
+        device.commands = vec![0x00, 0x21];
+        let mut dfu = DfuSync::new(&device, mock::ADDRESS);
+        let commands = dfu.get_commands().unwrap();
+        assert!(!commands.erase());
+        device.take_requests();
+        assert!(matches!(
+            dfu.download_from_slice(&[0x42; 300]),
+            Err(mock::MockError::Dfu(Error::UnsupportedCommand(0x41)))
+        ));
+        assert_eq!(device.take_requests(), vec![]);
+    }
+
+    #[test]
+    fn verify_not_readable() {
+        let mut device = Device::new(Protocol::DfuSe);
+        device.memory_map =
+            memory_layout::DfuseMemory::try_from("@Flash/0x08000000/01*001Kg,01*001Kf")
+                .unwrap()
+                .memory_map();
+        let mut dfu = DfuSync::new(&device, mock::ADDRESS).with_verify(true);
+
+        // the firmware is not downloaded if it cannot be read back
+        assert!(matches!(
+            dfu.download_from_slice(&[0x42; 0x800]),
+            Err(mock::MockError::Dfu(Error::NotReadable(0x08000400)))
+        ));
+        assert_eq!(device.take_requests(), vec![]);
+
+        dfu.download_from_slice(&[0x42; 0x400]).unwrap();
+    }
+
+    #[test]
+    fn verify_mismatch() {
+        for (protocol, address) in [(Protocol::Dfu, 0), (Protocol::DfuSe, mock::ADDRESS)] {
+            let device = Device::new(protocol);
+            let firmware = vec![0x42; 300];
+            let mut dfu = DfuSync::new(&device, address);
+            dfu.download_from_slice(&firmware).unwrap();
+            dfu.verify(&[(address, &firmware)]).unwrap();
+
+            // the first differing address and the number of differing bytes are reported
+            for offset in [10, 11, 290] {
+                device.memory.borrow_mut()[offset] = 0x00;
+            }
+            assert!(matches!(
+                dfu.verify(&[(address, &firmware)]),
+                Err(mock::MockError::Dfu(Error::VerifyMismatch { address: first, count: 3 }))
+                    if first == address + 10
+            ));
+        }
+    }
+
+    #[test]
+    fn device_check() {
+        let mut device = Device::new(Protocol::DfuSe);
+        device.device_ids = Some((0x0483, 0xdf11));
+        let firmware = suffix::add(
+            &[0x42; 300],
+            suffix::Suffix::new(0x0483, 0x5740, 0xffff, 0x0100),
+        )
+        .unwrap();
+        let file = dfuse_file::DfuseFileWriter::new()
+            .with_ids(0x0483, 0x5740)
+            .with_target(dfuse_file::TargetImage {
+                alt_setting: 0,
+                name: None,
+                elements: vec![(mock::ADDRESS, &[0x42; 300])],
+            })
+            .to_vec()
+            .unwrap();
+
+        let mut dfu = DfuSync::new(&device, mock::ADDRESS).with_device_check(true);
+        for result in [
+            dfu.download_from_slice(&firmware),
+            dfu.download_dfuse_file(&file, 0),
+        ] {
+            assert!(matches!(
+                result,
+                Err(mock::MockError::Dfu(Error::DeviceMismatch {
+                    vendor_id: 0x0483,
+                    product_id: 0x5740,
+                }))
+            ));
+        }
+        assert_eq!(device.take_requests(), vec![]);
+
+        // the suffix is stripped without the check
+        let mut dfu = DfuSync::new(&device, mock::ADDRESS);
+        dfu.download_from_slice(&firmware).unwrap();
+        dfu.download_dfuse_file(&file, 0).unwrap();
+        assert_eq!(device.memory.borrow()[..], [0x42; 300]);
+    }
+
+    #[test]
+    fn download_dfuse_file() {
+        let device = Device::new(Protocol::DfuSe);
+        let file = dfuse_file::DfuseFileWriter::new()
+            .with_target(dfuse_file::TargetImage {
+                alt_setting: 0,
+                name: Some("Internal Flash"),
+                elements: vec![
+                    (mock::ADDRESS + 0x800, &[0x43; 100]),
+                    (mock::ADDRESS, &[0x42; 300]),
+                ],
+            })
+            .with_target(dfuse_file::TargetImage {
+                alt_setting: 1,
+                name: None,
+                elements: vec![(mock::ADDRESS, &[0x44; 10])],
+            })
+            .to_vec()
+            .unwrap();
+        let mut dfu = DfuSync::new(&device, mock::ADDRESS);
+
+        // each element of the target is downloaded at its own address
+        dfu.download_dfuse_file(&file, 0).unwrap();
+        let requests = device.take_requests();
+        assert_eq!(
+            requests
+                .into_iter()
+                .filter(|request| matches!(request, Request::Erase(_) | Request::SetAddress(_)))
+                .collect::<Vec<_>>(),
+            vec![
+                Request::Erase(mock::ADDRESS),
+                Request::SetAddress(mock::ADDRESS),
+                Request::Erase(mock::ADDRESS + 0x800),
+                Request::SetAddress(mock::ADDRESS + 0x800),
+            ]
+        );
+        let memory = device.memory.borrow();
+        assert_eq!(memory[..300], [0x42; 300]);
+        assert!(memory[300..0x800].iter().all(|byte| *byte == 0xff));
+        assert_eq!(memory[0x800..], [0x43; 100]);
+        drop(memory);
+
+        assert!(matches!(
+            dfu.download_dfuse_file(&file, 2),
+            Err(mock::MockError::Dfu(Error::DfuseFile(
+                dfuse_file::Error::TargetNotFound(2)
+            )))
+        ));
+        assert_eq!(device.take_requests(), vec![]);
+    }
+
+    #[test]
+    fn download_ihex() {
+        let device = Device::new(Protocol::DfuSe);
+        let mut dfu = DfuSync::new(&device, mock::ADDRESS);
+
+        dfu.download_ihex(":020000040800F2\n:0400000001020304F2\n:00000001FF")
+            .unwrap();
+        assert_eq!(device.memory.borrow()[..4], [1, 2, 3, 4]);
+        assert!(matches!(
+            dfu.download_ihex(":020000040800F2\n:0400000001020304F2"),
+            Err(mock::MockError::Dfu(Error::IntelHex(
+                ihex::Error::MissingEndOfFile
+            )))
+        ));
+    }
+
+    #[test]
+    fn status_description() {
+        for (i_string, description) in [(1, "Flash locked"), (0, "")] {
+            let device = Device::new(Protocol::Dfu);
+            device.errors.borrow_mut().push_back((0x0b, i_string));
+            let mut dfu = DfuSync::new(&device, 0);
+            assert!(matches!(
+                dfu.download_from_slice(&[0x42; 300]),
+                Err(mock::MockError::Dfu(Error::StatusError {
+                    status: Status::ErrVendor,
+                    index,
+                    description: actual,
+                })) if index == i_string && actual.as_str() == description
+            ));
+        }
+    }
 }
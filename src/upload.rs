@@ -0,0 +1,346 @@
+use super::*;
+
+const REQUEST_TYPE: u8 = 0b00100001;
+const DFU_UPLOAD: u8 = 2;
+
+/// Starting point to upload a firmware from a device.
+#[must_use]
+pub struct Start<'dfu, IO: DfuIo> {
+    pub(crate) dfu: &'dfu DfuSansIo<IO>,
+    pub(crate) address: Option<u32>,
+    pub(crate) length: u32,
+    pub(crate) aborted: bool,
+}
+
+impl<'dfu, IO: DfuIo> ChainedCommand for Start<'dfu, IO> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<UploadLoop<'dfu, IO>, Error>;
+
+    fn chain(
+        self,
+        get_status::GetStatusMessage {
+            status: _,
+            poll_timeout,
+            state,
+            index: _,
+        }: Self::Arg,
+    ) -> Self::Into {
+        let abort = abort::interrupted(state) && !self.aborted;
+
+        if state == State::DfuIdle || abort {
+            Ok(UploadLoop {
+                dfu: self.dfu,
+                address: self.address,
+                length: self.length,
+                copied_pos: 0,
+                // DfuSe reads from ((wBlockNum - 2) * wTransferSize) + address pointer.
+                block_num: if self.address.is_some() { 2 } else { 0 },
+                poll_timeout,
+                address_set: self.address.is_none(),
+                eof: false,
+                abort,
+            })
+        } else {
+            Err(Error::InvalidState {
+                got: state,
+                expected: State::DfuIdle,
+            })
+        }
+    }
+}
+
+/// Upload loop.
+#[must_use]
+pub struct UploadLoop<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+    address: Option<u32>,
+    length: u32,
+    copied_pos: u32,
+    block_num: u16,
+    poll_timeout: u64,
+    address_set: bool,
+    eof: bool,
+    abort: bool,
+}
+
+impl<'dfu, IO: DfuIo> UploadLoop<'dfu, IO> {
+    /// Get the next step in the upload loop.
+    ///
+    /// The transfer of an interrupted session is aborted first (see [`Step::Abort`]).
+    pub fn next(self) -> Step<'dfu, IO> {
+        if self.abort {
+            Step::Abort(abort::Abort::start_over(
+                self.dfu,
+                Start {
+                    dfu: self.dfu,
+                    address: self.address,
+                    length: self.length,
+                    aborted: true,
+                },
+            ))
+        } else if self.eof {
+            Step::Break
+        } else if self.copied_pos >= self.length {
+            Step::Finish(abort::Abort {
+                dfu: self.dfu,
+                chained_command: UploadLoop { eof: true, ..self },
+            })
+        } else if let (false, Some(address)) = (self.address_set, self.address) {
+            Step::SetAddress(SetAddress {
+                dfu: self.dfu,
+                address,
+                length: self.length,
+                block_num: self.block_num,
+            })
+        } else {
+            let poll_timeout = self.poll_timeout;
+
+            Step::UploadChunk(
+                UploadChunk {
+                    dfu: self.dfu,
+                    address: self.address,
+                    length: self.length,
+                    copied_pos: self.copied_pos,
+                    block_num: self.block_num,
+                },
+                poll_timeout,
+            )
+        }
+    }
+}
+
+/// Upload step in the loop.
+#[allow(missing_docs)]
+pub enum Step<'dfu, IO: DfuIo> {
+    Break,
+    /// A previous transfer must be aborted before uploading.
+    Abort(abort::Abort<'dfu, IO, get_status::GetStatus<'dfu, IO, Start<'dfu, IO>>>),
+    SetAddress(SetAddress<'dfu, IO>),
+    /// A chunk must be read from the device after waiting for the poll timeout.
+    UploadChunk(UploadChunk<'dfu, IO>, u64),
+    /// The requested length has been read: the device stays in `dfuUPLOAD-IDLE` until the
+    /// transfer is aborted.
+    Finish(abort::Abort<'dfu, IO, UploadLoop<'dfu, IO>>),
+}
+
+/// Set the address pointer for a DfuSe upload.
+#[must_use]
+pub struct SetAddress<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+    address: u32,
+    length: u32,
+    block_num: u16,
+}
+
+impl<'dfu, IO: DfuIo> SetAddress<'dfu, IO> {
+    /// Set the address pointer for upload.
+    ///
+    /// The device must be brought back to `dfuIDLE` with [`abort::Abort`] after the command
+    /// completed as uploads are not allowed in `dfuDNLOAD-IDLE`.
+    pub fn set_address(
+        self,
+    ) -> Result<
+        (
+            get_status::WaitState<'dfu, IO, abort::Abort<'dfu, IO, UploadLoop<'dfu, IO>>>,
+            IO::Write,
+        ),
+        IO::Error,
+    > {
+        let next = get_status::WaitState::new(
+            self.dfu,
+            State::DfuDnloadIdle,
+            abort::Abort {
+                dfu: self.dfu,
+                chained_command: UploadLoop {
+                    dfu: self.dfu,
+                    address: Some(self.address),
+                    length: self.length,
+                    copied_pos: 0,
+                    block_num: self.block_num,
+                    poll_timeout: 0,
+                    address_set: true,
+                    eof: false,
+                    abort: false,
+                },
+            },
+        );
+        let res = self.dfu.io.write_control(
+            REQUEST_TYPE,
+            download::DFU_DNLOAD,
+            0,
+            &<[u8; 5]>::from(download::DownloadCommandSetAddress(self.address)),
+        )?;
+
+        Ok((next, res))
+    }
+}
+
+/// Upload a chunk of data from the device.
+#[must_use]
+pub struct UploadChunk<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+    address: Option<u32>,
+    length: u32,
+    copied_pos: u32,
+    block_num: u16,
+}
+
+impl<'dfu, IO: DfuIo> UploadChunk<'dfu, IO> {
+    /// Upload a chunk of data from the device into the buffer.
+    pub fn upload(
+        self,
+        buffer: &mut [u8],
+    ) -> Result<(UploadChunkRecv<'dfu, IO>, IO::Read), IO::Error> {
+        use core::convert::TryFrom;
+
+        let len = u32::try_from(buffer.len())
+            .unwrap_or(u32::MAX)
+            .min(self.dfu.io.functional_descriptor().transfer_size as u32)
+            .min(self.length - self.copied_pos);
+
+        let next = UploadChunkRecv {
+            dfu: self.dfu,
+            address: self.address,
+            length: self.length,
+            copied_pos: self.copied_pos,
+            block_num: self.block_num,
+            requested: len,
+        };
+        let res = self.dfu.io.read_control(
+            REQUEST_TYPE,
+            DFU_UPLOAD,
+            self.block_num,
+            &mut buffer[..len as usize],
+        )?;
+
+        Ok((next, res))
+    }
+}
+
+/// Read the chunk after getting it from the device.
+#[must_use]
+pub struct UploadChunkRecv<'dfu, IO: DfuIo> {
+    dfu: &'dfu DfuSansIo<IO>,
+    address: Option<u32>,
+    length: u32,
+    copied_pos: u32,
+    block_num: u16,
+    requested: u32,
+}
+
+impl<'dfu, IO: DfuIo> UploadChunkRecv<'dfu, IO> {
+    /// Chain this command into the upload loop using the number of bytes received.
+    ///
+    /// A chunk shorter than requested marks the end of the upload.
+    pub fn chain(self, len: usize) -> Result<UploadLoop<'dfu, IO>, Error> {
+        use core::convert::TryFrom;
+
+        let len = u32::try_from(len)
+            .ok()
+            .filter(|len| *len <= self.requested)
+            .ok_or(Error::BufferTooBig {
+                got: len,
+                expected: self.requested as usize,
+            })?;
+
+        Ok(UploadLoop {
+            dfu: self.dfu,
+            address: self.address,
+            length: self.length,
+            copied_pos: self.copied_pos + len,
+            block_num: self
+                .block_num
+                .checked_add(1)
+                .ok_or(Error::MaximumChunksExceeded)?,
+            poll_timeout: 0,
+            address_set: true,
+            eof: len < self.requested,
+            abort: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{Device, Request};
+    use std::prelude::v1::*;
+
+    fn firmware(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn upload() {
+        let device = Device::new(Protocol::Dfu);
+        *device.memory.borrow_mut() = firmware(1000);
+        let mut dfu = sync::DfuSync::new(&device, 0);
+
+        // the transfer is aborted once the requested length has been read
+        assert_eq!(dfu.upload_to_vec(600).unwrap(), firmware(600));
+        assert_eq!(
+            device.take_requests(),
+            vec![
+                Request::ClrStatus,
+                Request::Upload(0, 256),
+                Request::Upload(1, 256),
+                Request::Upload(2, 88),
+                Request::Abort,
+            ]
+        );
+        assert_eq!(device.state.get(), State::DfuIdle);
+
+        // a short frame ends the transfer
+        assert_eq!(dfu.upload_to_vec(2000).unwrap(), firmware(1000));
+        assert_eq!(
+            device.take_requests(),
+            vec![
+                Request::ClrStatus,
+                Request::Upload(0, 256),
+                Request::Upload(1, 256),
+                Request::Upload(2, 256),
+                Request::Upload(3, 256),
+            ]
+        );
+        assert_eq!(device.state.get(), State::DfuIdle);
+    }
+
+    #[test]
+    fn upload_dfuse() {
+        let device = Device::new(Protocol::DfuSe);
+        *device.memory.borrow_mut() = firmware(1000);
+        let mut dfu = sync::DfuSync::new(&device, mock::ADDRESS);
+
+        let mut data = Vec::new();
+        dfu.upload_dfuse(&mut data, mock::ADDRESS + 256, 300)
+            .unwrap();
+        assert_eq!(data, firmware(556)[256..]);
+        assert_eq!(
+            device.take_requests(),
+            vec![
+                Request::ClrStatus,
+                Request::SetAddress(mock::ADDRESS + 256),
+                Request::Abort,
+                Request::Upload(2, 256),
+                Request::Upload(3, 44),
+                Request::Abort,
+            ]
+        );
+        assert_eq!(device.state.get(), State::DfuIdle);
+
+        // the memory is read from the address of the device
+        assert_eq!(dfu.upload_to_vec(300).unwrap(), firmware(300));
+        assert_eq!(
+            device.take_requests()[1],
+            Request::SetAddress(mock::ADDRESS)
+        );
+
+        let device = Device::new(Protocol::Dfu);
+        let mut dfu = sync::DfuSync::new(&device, mock::ADDRESS);
+        assert!(matches!(
+            dfu.upload_dfuse(Vec::new(), mock::ADDRESS, 300),
+            Err(mock::MockError::Dfu(Error::DfuseRequired))
+        ));
+        assert_eq!(device.take_requests(), vec![]);
+    }
+}